@@ -99,6 +99,22 @@ async fn start(uri: &str) -> Result<(), Box<dyn std::error::Error>> {
                         .await
                         .unwrap();
                 }
+                walstream::server_message::Msg::Begin(ev) => {
+                    println!("BEGIN xid={}", ev.xid);
+                }
+                walstream::server_message::Msg::Commit(ev) => {
+                    println!("COMMIT xid={} rows={}", ev.xid, ev.row_count);
+
+                    ack_tx
+                        .send(ClientMessage {
+                            msg: Some(walstream::client_message::Msg::Ack(ClientAck {
+                                pg_lsn: ev.pg_lsn,
+                            })),
+                        })
+                        .await
+                        .unwrap();
+                }
+                _ => {}
             },
             Ok(_) => {}
             Err(e) => {
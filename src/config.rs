@@ -0,0 +1,617 @@
+use crate::source;
+use clap::ArgMatches;
+use clap::parser::ValueSource;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use toml::{Table, Value};
+
+/// Settings of the top-level table
+const CONFIG_KEYS: &[&str] = &[
+    "connection",
+    "data_dir",
+    "transaction_memory_limit",
+    "server",
+    "log",
+    "replication",
+    "sources",
+];
+
+const SERVER_KEYS: &[&str] = &["host", "port"];
+
+const LOG_KEYS: &[&str] = &["dir", "segment_size", "retention_bytes", "retention_hours"];
+
+/// Options of the `replication` table, which every source can override
+const REPLICATION_KEYS: &[&str] = &[
+    "temporary",
+    "streaming",
+    "two_phase",
+    "messages",
+    "binary",
+    "snapshot",
+    "lossless",
+    "signal_table",
+    "snapshot_chunk_size",
+];
+
+const SOURCE_KEYS: &[&str] = &["name", "connection", "publication", "slot"];
+
+/// Settings read from a `--config` file. Flags and environment variables take precedence over
+/// them, and they take precedence over the defaults of the flags.
+#[derive(Debug, Default)]
+pub struct Config {
+    /// Connection of the commands, and of sources without a connection of their own
+    pub connection: Option<String>,
+    pub data_dir: Option<String>,
+    pub transaction_memory_limit: Option<usize>,
+    pub server: ServerConfig,
+    pub log: LogConfig,
+    /// Options of all sources
+    pub replication: ReplicationConfig,
+    pub sources: Vec<SourceConfig>,
+}
+
+/// gRPC server
+#[derive(Debug, Default)]
+pub struct ServerConfig {
+    pub host: Option<String>,
+    pub port: Option<u32>,
+}
+
+/// Durable event log and its retention
+#[derive(Debug, Default)]
+pub struct LogConfig {
+    pub dir: Option<String>,
+    pub segment_size: Option<u64>,
+    pub retention_bytes: Option<u64>,
+    pub retention_hours: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ReplicationConfig {
+    pub temporary: Option<bool>,
+    pub streaming: Option<bool>,
+    pub two_phase: Option<bool>,
+    pub messages: Option<bool>,
+    pub binary: Option<bool>,
+    pub snapshot: Option<bool>,
+    pub lossless: Option<bool>,
+    pub signal_table: Option<String>,
+    pub snapshot_chunk_size: Option<usize>,
+}
+
+/// Database to stream, options left out are taken from the `replication` table
+#[derive(Debug, Clone)]
+pub struct SourceConfig {
+    pub name: String,
+    pub connection: Option<String>,
+    pub publication: String,
+    pub slot: String,
+    pub replication: ReplicationConfig,
+}
+
+/// Problem with a setting, `path` is empty for errors of the whole file
+#[derive(Debug)]
+pub struct ConfigError {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// Every problem found in a config file, one per line
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+impl Config {
+    /// Read and validate a TOML config file, reporting all errors instead of only the first
+    pub fn load(path: &Path) -> Result<Self, ConfigErrors> {
+        let file_error = |message: String| {
+            ConfigErrors(vec![ConfigError {
+                path: String::new(),
+                message,
+            }])
+        };
+
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| file_error(format!("Failed to read {}: {}", path.display(), e)))?;
+        let table = text
+            .parse::<Table>()
+            .map_err(|e| file_error(e.to_string().trim_end().to_string()))?;
+
+        let mut reader = Reader::default();
+        let config = reader.config(&table);
+        if reader.errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigErrors(reader.errors))
+        }
+    }
+}
+
+/// Value of a flag, or the one from the config file if the flag was neither given on the
+/// command line nor through its environment variable
+pub fn setting<T>(matches: &ArgMatches, id: &str, flag: Option<T>, config: Option<T>) -> Option<T> {
+    match matches.value_source(id) {
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable) => flag,
+        _ => config.or(flag),
+    }
+}
+
+/// Reads typed settings from the TOML tables, collecting errors instead of stopping at the
+/// first one
+#[derive(Debug, Default)]
+struct Reader {
+    errors: Vec<ConfigError>,
+}
+
+impl Reader {
+    fn config(&mut self, table: &Table) -> Config {
+        self.check_keys(table, "", CONFIG_KEYS);
+        let connection = self.string(table, "", "connection");
+        let data_dir = self.string(table, "", "data_dir");
+        let transaction_memory_limit = self
+            .size(table, "", "transaction_memory_limit")
+            .map(|limit| limit as usize);
+
+        let server = self
+            .table(table, "", "server")
+            .map(|server| {
+                self.check_keys(server, "server", SERVER_KEYS);
+                ServerConfig {
+                    host: self.string(server, "server", "host"),
+                    port: self
+                        .integer(server, "server", "port", 1, 65535)
+                        .map(|port| port as u32),
+                }
+            })
+            .unwrap_or_default();
+
+        let log = self
+            .table(table, "", "log")
+            .map(|log| {
+                self.check_keys(log, "log", LOG_KEYS);
+                LogConfig {
+                    dir: self.string(log, "log", "dir"),
+                    segment_size: self.size(log, "log", "segment_size"),
+                    retention_bytes: self.size(log, "log", "retention_bytes"),
+                    retention_hours: self.size(log, "log", "retention_hours"),
+                }
+            })
+            .unwrap_or_default();
+
+        let replication = self
+            .table(table, "", "replication")
+            .map(|replication| {
+                self.check_keys(replication, "replication", REPLICATION_KEYS);
+                self.replication(replication, "replication")
+            })
+            .unwrap_or_default();
+
+        let mut sources = Vec::new();
+        match table.get("sources") {
+            None => {}
+            Some(Value::Array(entries)) => {
+                let mut names = HashSet::new();
+                for (i, entry) in entries.iter().enumerate() {
+                    let path = format!("sources[{}]", i);
+                    let Value::Table(entry) = entry else {
+                        self.type_error(&path, "a table", entry);
+                        continue;
+                    };
+                    if let Some(source) = self.source(entry, &path, &mut names) {
+                        sources.push(source);
+                    }
+                }
+            }
+            Some(value) => self.type_error("sources", "an array of tables", value),
+        }
+
+        Config {
+            connection,
+            data_dir,
+            transaction_memory_limit,
+            server,
+            log,
+            replication,
+            sources,
+        }
+    }
+
+    fn replication(&mut self, table: &Table, path: &str) -> ReplicationConfig {
+        ReplicationConfig {
+            temporary: self.boolean(table, path, "temporary"),
+            streaming: self.boolean(table, path, "streaming"),
+            two_phase: self.boolean(table, path, "two_phase"),
+            messages: self.boolean(table, path, "messages"),
+            binary: self.boolean(table, path, "binary"),
+            snapshot: self.boolean(table, path, "snapshot"),
+            lossless: self.boolean(table, path, "lossless"),
+            signal_table: self.string(table, path, "signal_table"),
+            snapshot_chunk_size: self
+                .size(table, path, "snapshot_chunk_size")
+                .map(|size| size as usize),
+        }
+    }
+
+    /// Source of the `sources` array, `None` if a required setting is missing or invalid
+    fn source(
+        &mut self,
+        table: &Table,
+        path: &str,
+        names: &mut HashSet<String>,
+    ) -> Option<SourceConfig> {
+        let known: Vec<_> = SOURCE_KEYS
+            .iter()
+            .chain(REPLICATION_KEYS)
+            .copied()
+            .collect();
+        self.check_keys(table, path, &known);
+
+        let name = self.required_string(table, path, "name");
+        if let Some(name) = &name {
+            if let Err(e) = source::check_name(name) {
+                self.error(&join(path, "name"), e);
+            } else if !names.insert(name.clone()) {
+                self.error(
+                    &join(path, "name"),
+                    format!("Duplicate source name '{}'", name),
+                );
+            }
+        }
+        let connection = self.string(table, path, "connection");
+        let publication = self.required_string(table, path, "publication");
+        let slot = self.required_string(table, path, "slot");
+        let replication = self.replication(table, path);
+
+        Some(SourceConfig {
+            name: name?,
+            connection,
+            publication: publication?,
+            slot: slot?,
+            replication,
+        })
+    }
+
+    fn check_keys(&mut self, table: &Table, path: &str, known: &[&str]) {
+        for key in table.keys() {
+            if !known.contains(&key.as_str()) {
+                self.error(&join(path, key), "Unknown setting");
+            }
+        }
+    }
+
+    fn table<'t>(&mut self, table: &'t Table, path: &str, key: &str) -> Option<&'t Table> {
+        match table.get(key)? {
+            Value::Table(value) => Some(value),
+            value => {
+                self.type_error(&join(path, key), "a table", value);
+                None
+            }
+        }
+    }
+
+    /// String with environment variables interpolated
+    fn string(&mut self, table: &Table, path: &str, key: &str) -> Option<String> {
+        let path = join(path, key);
+        match table.get(key)? {
+            Value::String(value) if value.is_empty() => {
+                self.error(&path, "Must not be empty");
+                None
+            }
+            Value::String(value) => match interpolate(value) {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    self.error(&path, e);
+                    None
+                }
+            },
+            value => {
+                self.type_error(&path, "a string", value);
+                None
+            }
+        }
+    }
+
+    fn required_string(&mut self, table: &Table, path: &str, key: &str) -> Option<String> {
+        if !table.contains_key(key) {
+            self.error(&join(path, key), "Missing required setting");
+            return None;
+        }
+        self.string(table, path, key)
+    }
+
+    fn boolean(&mut self, table: &Table, path: &str, key: &str) -> Option<bool> {
+        match table.get(key)? {
+            Value::Boolean(value) => Some(*value),
+            value => {
+                self.type_error(&join(path, key), "a boolean", value);
+                None
+            }
+        }
+    }
+
+    fn integer(&mut self, table: &Table, path: &str, key: &str, min: i64, max: i64) -> Option<u64> {
+        let path = join(path, key);
+        match table.get(key)? {
+            Value::Integer(value) if (min..=max).contains(value) => Some(*value as u64),
+            Value::Integer(_) if max == i64::MAX => {
+                self.error(&path, format!("Must be at least {}", min));
+                None
+            }
+            Value::Integer(_) => {
+                self.error(&path, format!("Must be between {} and {}", min, max));
+                None
+            }
+            value => {
+                self.type_error(&path, "an integer", value);
+                None
+            }
+        }
+    }
+
+    /// Positive integer, e.g. a number of bytes
+    fn size(&mut self, table: &Table, path: &str, key: &str) -> Option<u64> {
+        self.integer(table, path, key, 1, i64::MAX)
+    }
+
+    fn type_error(&mut self, path: &str, expected: &str, value: &Value) {
+        self.error(
+            path,
+            format!("Expected {}, found {}", expected, value.type_str()),
+        );
+    }
+
+    fn error(&mut self, path: &str, message: impl Into<String>) {
+        self.errors.push(ConfigError {
+            path: path.to_string(),
+            message: message.into(),
+        });
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+/// Replace `${NAME}` with the environment variable `NAME`, or with `default` in
+/// `${NAME:-default}` if it is not set. `$$` is a literal `$`.
+fn interpolate(value: &str) -> Result<String, String> {
+    let mut interpolated = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        interpolated.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(after) = rest.strip_prefix("$$") {
+            interpolated.push('$');
+            rest = after;
+            continue;
+        }
+        let Some(variable) = rest.strip_prefix("${") else {
+            interpolated.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let end = variable
+            .find('}')
+            .ok_or_else(|| "Unterminated '${' in value".to_string())?;
+        let (name, default) = match variable[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&variable[..end], None),
+        };
+        if name.is_empty() {
+            return Err("Empty environment variable name in '${}'".to_string());
+        }
+
+        match (std::env::var(name), default) {
+            (Ok(value), _) => interpolated.push_str(&value),
+            (Err(_), Some(default)) => interpolated.push_str(default),
+            (Err(_), None) => return Err(format!("Environment variable {} is not set", name)),
+        }
+        rest = &variable[end + 1..];
+    }
+    interpolated.push_str(rest);
+    Ok(interpolated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Not set in any test environment
+    const UNSET: &str = "WALSTREAM_TEST_UNSET_VARIABLE";
+
+    fn read(toml: &str) -> (Config, Vec<String>) {
+        let mut reader = Reader::default();
+        let config = reader.config(&toml.parse::<Table>().unwrap());
+        let errors = reader.errors.iter().map(ToString::to_string).collect();
+        (config, errors)
+    }
+
+    #[test]
+    fn interpolates_variables() {
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(interpolate("plain").unwrap(), "plain");
+        assert_eq!(interpolate("${PATH}").unwrap(), path);
+        assert_eq!(interpolate("a${PATH}b").unwrap(), format!("a{}b", path));
+        assert_eq!(
+            interpolate(&format!("${{{}:-db}}:5432", UNSET)).unwrap(),
+            "db:5432"
+        );
+        assert_eq!(interpolate(&format!("${{{}:-}}", UNSET)).unwrap(), "");
+        assert_eq!(interpolate("${PATH:-unused}").unwrap(), path);
+    }
+
+    #[test]
+    fn keeps_literal_dollars() {
+        assert_eq!(interpolate("$$").unwrap(), "$");
+        assert_eq!(interpolate("pa$$word").unwrap(), "pa$word");
+        assert_eq!(interpolate("$${PATH}").unwrap(), "${PATH}");
+        assert_eq!(interpolate("$$$$").unwrap(), "$$");
+        assert_eq!(interpolate("cost $5").unwrap(), "cost $5");
+        assert_eq!(interpolate("trailing $").unwrap(), "trailing $");
+    }
+
+    #[test]
+    fn rejects_invalid_interpolation() {
+        assert_eq!(
+            interpolate("${PATH").unwrap_err(),
+            "Unterminated '${' in value"
+        );
+        assert_eq!(
+            interpolate("${}").unwrap_err(),
+            "Empty environment variable name in '${}'"
+        );
+        assert_eq!(
+            interpolate("${:-default}").unwrap_err(),
+            "Empty environment variable name in '${}'"
+        );
+        assert_eq!(
+            interpolate(&format!("${{{}}}", UNSET)).unwrap_err(),
+            format!("Environment variable {} is not set", UNSET)
+        );
+    }
+
+    #[test]
+    fn reads_settings_and_sources() {
+        let (config, errors) = read(&format!(
+            r#"
+            connection = "postgresql://localhost/${{{unset}:-postgres}}"
+            transaction_memory_limit = 1024
+
+            [server]
+            port = 50052
+
+            [log]
+            retention_hours = 24
+
+            [replication]
+            lossless = true
+
+            [[sources]]
+            name = "orders"
+            publication = "pub"
+            slot = "orders_slot"
+            binary = true
+            "#,
+            unset = UNSET
+        ));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            config.connection.as_deref(),
+            Some("postgresql://localhost/postgres")
+        );
+        assert_eq!(config.transaction_memory_limit, Some(1024));
+        assert_eq!(config.server.port, Some(50052));
+        assert_eq!(config.server.host, None);
+        assert_eq!(config.log.retention_hours, Some(24));
+        assert_eq!(config.replication.lossless, Some(true));
+
+        let [source] = config.sources.as_slice() else {
+            panic!("expected one source");
+        };
+        assert_eq!(source.name, "orders");
+        assert_eq!(source.connection, None);
+        assert_eq!(source.slot, "orders_slot");
+        assert_eq!(source.replication.binary, Some(true));
+        assert_eq!(source.replication.lossless, None);
+    }
+
+    #[test]
+    fn reports_every_error_with_its_path() {
+        let (_, errors) = read(&format!(
+            r#"
+            connection = ""
+            data_dir = "${{{unset}}}"
+            sinks = []
+
+            [server]
+            port = 70000
+            tls = true
+
+            [log]
+            segment_size = 0
+            retention_hours = "1d"
+
+            [replication]
+            lossless = "yes"
+
+            [[sources]]
+            name = "orders"
+            slot = "orders_slot"
+
+            [[sources]]
+            name = "orders"
+            publication = "pub"
+            slot = "other_slot"
+            transform = "upper"
+            "#,
+            unset = UNSET
+        ));
+        assert_eq!(
+            errors,
+            [
+                "sinks: Unknown setting".to_string(),
+                "connection: Must not be empty".to_string(),
+                format!("data_dir: Environment variable {} is not set", UNSET),
+                "server.tls: Unknown setting".to_string(),
+                "server.port: Must be between 1 and 65535".to_string(),
+                "log.segment_size: Must be at least 1".to_string(),
+                "log.retention_hours: Expected an integer, found string".to_string(),
+                "replication.lossless: Expected a boolean, found string".to_string(),
+                "sources[0].publication: Missing required setting".to_string(),
+                "sources[1].transform: Unknown setting".to_string(),
+                "sources[1].name: Duplicate source name 'orders'".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_values_of_the_wrong_shape() {
+        let (_, errors) = read("server = 1\nsources = [1]");
+        assert_eq!(
+            errors,
+            [
+                "server: Expected a table, found integer",
+                "sources[0]: Expected a table, found integer",
+            ]
+        );
+
+        let (_, errors) = read("[sources]\nname = \"orders\"");
+        assert_eq!(
+            errors,
+            ["sources: Expected an array of tables, found table"]
+        );
+
+        let (_, errors) = read("[[sources]]\nname = \"a b\"\npublication = \"p\"\nslot = \"s\"");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("sources[0].name: "), "{:?}", errors);
+    }
+}
@@ -0,0 +1,976 @@
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::iter::Peekable;
+use std::net::IpAddr;
+use std::str::Chars;
+use tokio_postgres::Client;
+use tokio_postgres::types::{Kind, Type};
+
+/// Days between Unix epoch (1970-01-01) and Postgres epoch (2000-01-01)
+const POSTGRES_EPOCH_OFFSET_DAYS: i64 = 10_957;
+
+const MICROS_PER_SECOND: i64 = 1_000_000;
+const MICROS_PER_DAY: i64 = 86_400 * MICROS_PER_SECOND;
+
+// Sign field of the binary numeric format
+const NUMERIC_NEG: u16 = 0x4000;
+const NUMERIC_NAN: u16 = 0xC000;
+const NUMERIC_PINF: u16 = 0xD000;
+const NUMERIC_NINF: u16 = 0xF000;
+
+/// Types to decode values of the user-defined types in `type_oids` as, by type OID.
+///
+/// A domain is sent in the format of its base type and an enum as its label, so domains are
+/// decoded as their base type and enums as `text`, in text and binary format alike. Arrays of
+/// them as arrays of the resolved element type. Other types are left out.
+pub async fn resolve_types(
+    client: &Client,
+    type_oids: &[u32],
+) -> Result<HashMap<u32, u32>, tokio_postgres::Error> {
+    let unknown: Vec<u32> = type_oids
+        .iter()
+        .copied()
+        .filter(|&oid| Type::from_oid(oid).is_none())
+        .collect();
+    if unknown.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    // Follows domains down to their base type, which may be a domain itself
+    let rows = client
+        .query(
+            "WITH RECURSIVE input AS (
+                 SELECT oid AS type_oid,
+                     typtype = 'b' AND typcategory = 'A' AND typelem <> 0 AS is_array,
+                     CASE WHEN typtype = 'b' AND typcategory = 'A' AND typelem <> 0
+                         THEN typelem ELSE oid END AS oid
+                 FROM pg_type WHERE oid = ANY($1::oid[])
+             ), base(type_oid, is_array, oid) AS (
+                 SELECT type_oid, is_array, oid FROM input
+                 UNION ALL
+                 SELECT base.type_oid, base.is_array, t.typbasetype
+                 FROM base JOIN pg_type t ON t.oid = base.oid WHERE t.typtype = 'd'
+             )
+             SELECT base.type_oid, CASE
+                 WHEN t.typtype = 'e' AND base.is_array THEN 'text[]'::regtype::oid
+                 WHEN t.typtype = 'e' THEN 'text'::regtype::oid
+                 WHEN base.is_array THEN t.typarray
+                 ELSE t.oid END
+             FROM base JOIN pg_type t ON t.oid = base.oid
+             WHERE t.typtype <> 'd'",
+            &[&unknown],
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .filter(|(type_oid, resolved)| type_oid != resolved)
+        .collect())
+}
+
+/// Decode a column value in Postgres text format into JSON based on the column type OID.
+///
+/// Numbers and booleans become JSON numbers and booleans, `json`/`jsonb` are embedded as
+/// nested JSON, arrays become JSON arrays, timestamps are formatted as ISO-8601 and `bytea`
+/// is base64 encoded. `numeric` is kept as a string to preserve precision. Values of other
+/// types unknown to `walstream` are passed through as strings, enums and domains are decoded as
+/// the type `resolve_types` gives for them.
+pub fn decode_text(type_oid: u32, value: &str) -> Value {
+    match Type::from_oid(type_oid) {
+        Some(ty) => decode_text_as(&ty, value),
+        None => Value::String(value.to_string()),
+    }
+}
+
+fn decode_text_as(ty: &Type, value: &str) -> Value {
+    if let Kind::Array(element) = ty.kind() {
+        return decode_array(element, value);
+    }
+
+    match ty.name() {
+        "bool" => Value::Bool(value == "t"),
+        "int2" | "int4" | "int8" | "oid" => value
+            .parse::<i64>()
+            .map(Value::from)
+            .unwrap_or_else(|_| Value::String(value.to_string())),
+        "float4" | "float8" => value
+            .parse::<f64>()
+            .map(float_value)
+            .unwrap_or_else(|_| Value::String(value.to_string())),
+        "json" | "jsonb" => {
+            serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+        }
+        "bytea" => decode_bytea(value),
+        "timestamp" | "timestamptz" => Value::String(iso_timestamp(value)),
+        _ => Value::String(value.to_string()),
+    }
+}
+
+/// Decode a column value in Postgres binary format into JSON based on the column type OID.
+///
+/// Produces the same JSON values as `decode_text`, except that `timestamptz` values are
+/// always in UTC. Enums and domains are decoded as the type `resolve_types` gives for them. The
+/// binary format of other types (e.g. `money`, bit strings, geometric, range and extension
+/// types) is specific to each type, their values are base64 encoded and tagged with the type
+/// as `{"type_oid": 1234, "binary": "..."}`.
+pub fn decode_binary(type_oid: u32, value: &[u8]) -> Value {
+    Type::from_oid(type_oid)
+        .and_then(|ty| decode_binary_as(&ty, value))
+        .unwrap_or_else(|| undecoded_binary(type_oid, value))
+}
+
+fn decode_binary_as(ty: &Type, value: &[u8]) -> Option<Value> {
+    match ty.kind() {
+        Kind::Array(element) => decode_binary_array(element, value),
+        _ => decode_binary_scalar(ty, value),
+    }
+}
+
+fn undecoded_binary(type_oid: u32, value: &[u8]) -> Value {
+    json!({"type_oid": type_oid, "binary": BASE64.encode(value)})
+}
+
+fn decode_binary_scalar(ty: &Type, value: &[u8]) -> Option<Value> {
+    let mut buf = value;
+
+    let decoded = match ty.name() {
+        "bool" => Value::Bool(take::<1>(&mut buf)?[0] != 0),
+        "text" | "varchar" | "bpchar" | "name" | "xml" => {
+            Value::String(std::str::from_utf8(value).ok()?.to_string())
+        }
+        "char" => match take::<1>(&mut buf)?[0] {
+            byte if byte.is_ascii() => Value::String(char::from(byte).to_string()),
+            _ => return None,
+        },
+        "int2" => Value::from(i16::from_be_bytes(take(&mut buf)?)),
+        "int4" => Value::from(i32::from_be_bytes(take(&mut buf)?)),
+        "int8" => Value::from(i64::from_be_bytes(take(&mut buf)?)),
+        "oid" => Value::from(u32::from_be_bytes(take(&mut buf)?)),
+        // Via the shortest decimal representation, so 0.1::float4 stays 0.1
+        "float4" => float_value(
+            f32::from_be_bytes(take(&mut buf)?)
+                .to_string()
+                .parse()
+                .ok()?,
+        ),
+        "float8" => float_value(f64::from_be_bytes(take(&mut buf)?)),
+        "numeric" => Value::String(decode_binary_numeric(&mut buf)?),
+        "uuid" => Value::String(format_uuid(&take::<16>(&mut buf)?)),
+        "date" => Value::String(format_date(i32::from_be_bytes(take(&mut buf)?))),
+        "time" => Value::String(format_time(i64::from_be_bytes(take(&mut buf)?))),
+        "timestamp" => Value::String(format_timestamp(i64::from_be_bytes(take(&mut buf)?), false)),
+        "timestamptz" => Value::String(format_timestamp(i64::from_be_bytes(take(&mut buf)?), true)),
+        "timetz" => {
+            let time = format_time(i64::from_be_bytes(take(&mut buf)?));
+            // The zone is sent in seconds west of UTC
+            let offset = i32::from_be_bytes(take(&mut buf)?);
+            Value::String(format!("{}{}", time, format_offset(-offset)))
+        }
+        "interval" => {
+            let micros = i64::from_be_bytes(take(&mut buf)?);
+            let days = i32::from_be_bytes(take(&mut buf)?);
+            let months = i32::from_be_bytes(take(&mut buf)?);
+            Value::String(format_interval(micros, days, months))
+        }
+        "inet" | "cidr" => Value::String(format_inet(&mut buf, ty.name() == "cidr")?),
+        "macaddr" => Value::String(format_macaddr(&take::<6>(&mut buf)?)),
+        "macaddr8" => Value::String(format_macaddr(&take::<8>(&mut buf)?)),
+        "bytea" => Value::String(BASE64.encode(value)),
+        "json" => serde_json::from_slice(value).ok()?,
+        // jsonb is prefixed with a format version, currently always 1
+        "jsonb" => match value.split_first()? {
+            (1, json) => serde_json::from_slice(json).ok()?,
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(decoded)
+}
+
+/// Decode an array in binary format: header, dimensions and length-prefixed elements
+fn decode_binary_array(element: &Type, value: &[u8]) -> Option<Value> {
+    let mut buf = value;
+
+    let ndim = i32::from_be_bytes(take(&mut buf)?);
+    let _has_nulls = i32::from_be_bytes(take(&mut buf)?);
+    let _element_oid = u32::from_be_bytes(take(&mut buf)?);
+
+    let mut dimensions = Vec::new();
+    for _ in 0..ndim {
+        dimensions.push(usize::try_from(i32::from_be_bytes(take(&mut buf)?)).ok()?);
+        let _lower_bound = i32::from_be_bytes(take(&mut buf)?);
+    }
+
+    if dimensions.is_empty() {
+        return Some(Value::Array(Vec::new()));
+    }
+
+    decode_binary_array_dimension(element, &dimensions, &mut buf)
+}
+
+fn decode_binary_array_dimension(
+    element: &Type,
+    dimensions: &[usize],
+    buf: &mut &[u8],
+) -> Option<Value> {
+    let mut items = Vec::new();
+
+    for _ in 0..dimensions[0] {
+        if dimensions.len() > 1 {
+            items.push(decode_binary_array_dimension(
+                element,
+                &dimensions[1..],
+                buf,
+            )?);
+            continue;
+        }
+
+        // Length of -1 marks a NULL element
+        let len = i32::from_be_bytes(take(buf)?);
+        match usize::try_from(len) {
+            Ok(len) => {
+                let (item, rest) = buf.split_at_checked(len)?;
+                *buf = rest;
+                items.push(
+                    decode_binary_as(element, item)
+                        .unwrap_or_else(|| undecoded_binary(element.oid(), item)),
+                );
+            }
+            Err(_) => items.push(Value::Null),
+        }
+    }
+
+    Some(Value::Array(items))
+}
+
+/// Decode numeric in binary format, a sequence of base 10000 digits, to an exact decimal string
+fn decode_binary_numeric(buf: &mut &[u8]) -> Option<String> {
+    let ndigits = i16::from_be_bytes(take(buf)?);
+    let weight = i16::from_be_bytes(take(buf)?) as i32;
+    let sign = u16::from_be_bytes(take(buf)?);
+    let dscale = u16::from_be_bytes(take(buf)?) as usize;
+
+    let mut digits = Vec::new();
+    for _ in 0..ndigits {
+        digits.push(i16::from_be_bytes(take(buf)?));
+    }
+
+    match sign {
+        NUMERIC_NAN => return Some("NaN".to_string()),
+        NUMERIC_PINF => return Some("Infinity".to_string()),
+        NUMERIC_NINF => return Some("-Infinity".to_string()),
+        _ => {}
+    }
+
+    // Digit at index i has weight `weight - i`, digits outside of the array are zero
+    let digit = |i: i32| {
+        usize::try_from(i)
+            .ok()
+            .and_then(|i| digits.get(i).copied())
+            .unwrap_or(0)
+    };
+
+    let mut numeric = String::new();
+    if sign == NUMERIC_NEG {
+        numeric.push('-');
+    }
+
+    if weight < 0 {
+        numeric.push('0');
+    } else {
+        write!(numeric, "{}", digit(0)).ok()?;
+        for i in 1..=weight {
+            write!(numeric, "{:04}", digit(i)).ok()?;
+        }
+    }
+
+    if dscale > 0 {
+        let mut fraction = String::new();
+        let mut i = weight + 1;
+        while fraction.len() < dscale {
+            write!(fraction, "{:04}", digit(i)).ok()?;
+            i += 1;
+        }
+        fraction.truncate(dscale);
+
+        numeric.push('.');
+        numeric.push_str(&fraction);
+    }
+
+    Some(numeric)
+}
+
+fn take<const N: usize>(buf: &mut &[u8]) -> Option<[u8; N]> {
+    let (head, rest) = buf.split_first_chunk::<N>()?;
+    *buf = rest;
+    Some(*head)
+}
+
+/// NaN and Infinity have no JSON representation and are returned as strings like in Postgres
+fn float_value(value: f64) -> Value {
+    match serde_json::Number::from_f64(value) {
+        Some(number) => Value::Number(number),
+        None if value.is_nan() => Value::String("NaN".to_string()),
+        None if value > 0.0 => Value::String("Infinity".to_string()),
+        None => Value::String("-Infinity".to_string()),
+    }
+}
+
+fn format_uuid(bytes: &[u8; 16]) -> String {
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Format days since Postgres epoch as 'YYYY-MM-DD'
+fn format_date(days: i32) -> String {
+    match days {
+        i32::MAX => "infinity".to_string(),
+        i32::MIN => "-infinity".to_string(),
+        days => {
+            let (date, bc) = format_civil_date(days as i64 + POSTGRES_EPOCH_OFFSET_DAYS);
+            if bc { format!("{} BC", date) } else { date }
+        }
+    }
+}
+
+/// Format days since Unix epoch as 'YYYY-MM-DD'. Like Postgres, years before 1 AD are counted
+/// back from 1 BC, which is flagged with `true`.
+fn format_civil_date(days: i64) -> (String, bool) {
+    let (year, month, day) = civil_from_days(days);
+    let bc = year <= 0;
+    let year = if bc { 1 - year } else { year };
+    (format!("{:04}-{:02}-{:02}", year, month, day), bc)
+}
+
+/// Format microseconds since midnight as 'HH:MM:SS[.ffffff]'
+fn format_time(micros: i64) -> String {
+    let seconds = micros / MICROS_PER_SECOND;
+    let mut time = format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+
+    // Like Postgres, print only the significant fractional digits
+    let fraction = micros % MICROS_PER_SECOND;
+    if fraction != 0 {
+        let fraction = format!(".{:06}", fraction);
+        time.push_str(fraction.trim_end_matches('0'));
+    }
+
+    time
+}
+
+/// Format microseconds since Postgres epoch as ISO-8601, in UTC when `with_offset` is set
+fn format_timestamp(micros: i64, with_offset: bool) -> String {
+    match micros {
+        i64::MAX => "infinity".to_string(),
+        i64::MIN => "-infinity".to_string(),
+        micros => {
+            let days = micros.div_euclid(MICROS_PER_DAY);
+            let (date, bc) = format_civil_date(days + POSTGRES_EPOCH_OFFSET_DAYS);
+
+            format!(
+                "{}T{}{}{}",
+                date,
+                format_time(micros.rem_euclid(MICROS_PER_DAY)),
+                if with_offset { "+00:00" } else { "" },
+                if bc { " BC" } else { "" }
+            )
+        }
+    }
+}
+
+/// Format an offset in seconds east of UTC like Postgres, e.g. '+02', '-03:30'
+fn format_offset(seconds: i32) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let seconds = seconds.unsigned_abs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+
+    match (minutes, seconds) {
+        (0, 0) => format!("{}{:02}", sign, hours),
+        (_, 0) => format!("{}{:02}:{:02}", sign, hours, minutes),
+        _ => format!("{}{:02}:{:02}:{:02}", sign, hours, minutes, seconds),
+    }
+}
+
+/// Format an interval like Postgres with the default `IntervalStyle = postgres`, e.g.
+/// '1 year 2 mons -3 days +04:05:06.5'
+fn format_interval(micros: i64, days: i32, months: i32) -> String {
+    match (micros, days, months) {
+        (i64::MAX, i32::MAX, i32::MAX) => return "infinity".to_string(),
+        (i64::MIN, i32::MIN, i32::MIN) => return "-infinity".to_string(),
+        _ => {}
+    }
+
+    let mut parts = Vec::new();
+    // After a negative field, positive fields are signed as well
+    let mut negative = false;
+    for (value, unit) in [(months / 12, "year"), (months % 12, "mon"), (days, "day")] {
+        if value == 0 {
+            continue;
+        }
+        parts.push(format!(
+            "{}{} {}{}",
+            if negative && value > 0 { "+" } else { "" },
+            value,
+            unit,
+            if value == 1 { "" } else { "s" }
+        ));
+        negative = value < 0;
+    }
+
+    if micros != 0 || parts.is_empty() {
+        let sign = match micros {
+            ..0 => "-",
+            _ if negative => "+",
+            _ => "",
+        };
+        // Hours are not wrapped at 24 like the time of day
+        let time = format_time(micros.checked_abs().unwrap_or(i64::MAX));
+        parts.push(format!("{}{}", sign, time));
+    }
+
+    parts.join(" ")
+}
+
+/// Format inet and cidr in binary format: family, netmask bits, cidr flag, length and address
+fn format_inet(buf: &mut &[u8], cidr: bool) -> Option<String> {
+    let [family, bits, _, len] = take::<4>(buf)?;
+    let (address, max_bits) = match (family, len) {
+        (2, 4) => (IpAddr::from(take::<4>(buf)?), 32),
+        (3, 16) => (IpAddr::from(take::<16>(buf)?), 128),
+        _ => return None,
+    };
+
+    // Like Postgres, inet leaves out the netmask of a single host
+    Some(if cidr || bits != max_bits {
+        format!("{}/{}", address, bits)
+    } else {
+        address.to_string()
+    })
+}
+
+fn format_macaddr(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Convert days since Unix epoch to a (year, month, day) date in the proleptic Gregorian
+/// calendar, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+/// Convert a timestamp in `DateStyle = ISO` output format, e.g. '2024-01-02 03:04:05.123+02',
+/// to ISO-8601, e.g. '2024-01-02T03:04:05.123+02:00'
+fn iso_timestamp(value: &str) -> String {
+    let (value, era) = match value.strip_suffix(" BC") {
+        Some(value) => (value, " BC"),
+        None => (value, ""),
+    };
+    let mut iso = value.replacen(' ', "T", 1);
+
+    // Offsets with whole hours are printed without minutes
+    if let Some(pos) = iso.rfind(['+', '-'])
+        && pos > "YYYY-MM-DD".len()
+        && iso.len() - pos == "+HH".len()
+    {
+        iso.push_str(":00");
+    }
+
+    iso.push_str(era);
+    iso
+}
+
+/// Decode `bytea` in hex output format, e.g. '\x0a0b', to base64
+fn decode_bytea(value: &str) -> Value {
+    match value.strip_prefix("\\x").and_then(decode_hex) {
+        Some(bytes) => Value::String(BASE64.encode(bytes)),
+        None => Value::String(value.to_string()),
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Decode an array literal such as '{1,2,NULL}' or '{{"a b",c},{d,e}}'
+fn decode_array(element: &Type, value: &str) -> Value {
+    // Arrays with non-default bounds are prefixed with their dimensions, e.g. '[0:1]={1,2}'
+    let literal = match value.find('=') {
+        Some(pos) if value.starts_with('[') => &value[pos + 1..],
+        _ => value,
+    };
+
+    let mut chars = literal.chars().peekable();
+    decode_array_dimension(element, &mut chars).unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+fn decode_array_dimension(element: &Type, chars: &mut Peekable<Chars>) -> Option<Value> {
+    if chars.next()? != '{' {
+        return None;
+    }
+
+    let mut items = Vec::new();
+    if chars.peek() == Some(&'}') {
+        chars.next();
+        return Some(Value::Array(items));
+    }
+
+    loop {
+        match chars.peek()? {
+            '{' => items.push(decode_array_dimension(element, chars)?),
+            '"' => {
+                chars.next();
+                let mut item = String::new();
+                loop {
+                    match chars.next()? {
+                        '\\' => item.push(chars.next()?),
+                        '"' => break,
+                        c => item.push(c),
+                    }
+                }
+                items.push(decode_text_as(element, &item));
+            }
+            _ => {
+                let mut item = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c == ',' || c == '}' {
+                        break;
+                    }
+                    item.push(c);
+                    chars.next();
+                }
+                items.push(match item.as_str() {
+                    "NULL" => Value::Null,
+                    item => decode_text_as(element, item),
+                });
+            }
+        }
+
+        match chars.next()? {
+            ',' => {}
+            '}' => return Some(Value::Array(items)),
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn binary(type_name: &str, value: &[u8]) -> Value {
+        let ty = [
+            Type::BOOL,
+            Type::INT2,
+            Type::INT4,
+            Type::INT8,
+            Type::FLOAT4,
+            Type::FLOAT8,
+            Type::NUMERIC,
+            Type::TEXT,
+            Type::BPCHAR,
+            Type::CHAR,
+            Type::UUID,
+            Type::DATE,
+            Type::TIME,
+            Type::TIMETZ,
+            Type::TIMESTAMP,
+            Type::TIMESTAMPTZ,
+            Type::INTERVAL,
+            Type::INET,
+            Type::CIDR,
+            Type::MACADDR,
+            Type::BYTEA,
+            Type::JSON,
+            Type::JSONB,
+            Type::MONEY,
+            Type::INT4_ARRAY,
+            Type::TEXT_ARRAY,
+        ]
+        .into_iter()
+        .find(|ty| ty.name() == type_name)
+        .unwrap();
+        decode_binary(ty.oid(), value)
+    }
+
+    fn numeric(weight: i16, sign: u16, dscale: u16, digits: &[i16]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend((digits.len() as i16).to_be_bytes());
+        bytes.extend(weight.to_be_bytes());
+        bytes.extend(sign.to_be_bytes());
+        bytes.extend(dscale.to_be_bytes());
+        for digit in digits {
+            bytes.extend(digit.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn interval(micros: i64, days: i32, months: i32) -> Vec<u8> {
+        [
+            &micros.to_be_bytes()[..],
+            &days.to_be_bytes(),
+            &months.to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    /// One-dimensional array with elements of `element_oid`, `None` for NULL
+    fn array(element_oid: u32, elements: &[Option<&[u8]>]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(1i32.to_be_bytes());
+        bytes.extend(i32::from(elements.contains(&None)).to_be_bytes());
+        bytes.extend(element_oid.to_be_bytes());
+        bytes.extend((elements.len() as i32).to_be_bytes());
+        bytes.extend(1i32.to_be_bytes());
+        for element in elements {
+            match element {
+                Some(element) => {
+                    bytes.extend((element.len() as i32).to_be_bytes());
+                    bytes.extend(*element);
+                }
+                None => bytes.extend((-1i32).to_be_bytes()),
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn text_scalars() {
+        assert_eq!(decode_text(Type::BOOL.oid(), "t"), json!(true));
+        assert_eq!(decode_text(Type::BOOL.oid(), "f"), json!(false));
+        assert_eq!(decode_text(Type::INT8.oid(), "-42"), json!(-42));
+        assert_eq!(decode_text(Type::FLOAT8.oid(), "1.5"), json!(1.5));
+        assert_eq!(decode_text(Type::FLOAT8.oid(), "NaN"), json!("NaN"));
+        assert_eq!(
+            decode_text(Type::FLOAT4.oid(), "-Infinity"),
+            json!("-Infinity")
+        );
+        assert_eq!(decode_text(Type::NUMERIC.oid(), "1.10"), json!("1.10"));
+        assert_eq!(
+            decode_text(Type::JSONB.oid(), r#"{"a": 1}"#),
+            json!({"a": 1})
+        );
+        assert_eq!(decode_text(Type::BYTEA.oid(), "\\x0a0b"), json!("Cgs="));
+        assert_eq!(decode_text(Type::TEXT.oid(), "NULL"), json!("NULL"));
+        // Unknown types, e.g. composite types, are passed through
+        assert_eq!(decode_text(999_999, "happy"), json!("happy"));
+    }
+
+    #[test]
+    fn text_timestamps() {
+        let timestamptz = Type::TIMESTAMPTZ.oid();
+        assert_eq!(
+            decode_text(timestamptz, "2024-01-02 03:04:05.123+02"),
+            json!("2024-01-02T03:04:05.123+02:00")
+        );
+        assert_eq!(
+            decode_text(timestamptz, "2024-01-02 03:04:05-03:30"),
+            json!("2024-01-02T03:04:05-03:30")
+        );
+        assert_eq!(
+            decode_text(timestamptz, "0044-03-15 12:00:00+00 BC"),
+            json!("0044-03-15T12:00:00+00:00 BC")
+        );
+        assert_eq!(
+            decode_text(Type::TIMESTAMP.oid(), "2024-01-02 03:04:05"),
+            json!("2024-01-02T03:04:05")
+        );
+        assert_eq!(decode_text(timestamptz, "infinity"), json!("infinity"));
+    }
+
+    #[test]
+    fn text_arrays() {
+        let int4_array = Type::INT4_ARRAY.oid();
+        assert_eq!(decode_text(int4_array, "{1,NULL,3}"), json!([1, null, 3]));
+        assert_eq!(decode_text(int4_array, "{}"), json!([]));
+        assert_eq!(decode_text(int4_array, "[0:1]={1,2}"), json!([1, 2]));
+        assert_eq!(
+            decode_text(Type::TEXT_ARRAY.oid(), r#"{{"a b",NULL},{"NULL","c\"d"}}"#),
+            json!([["a b", null], ["NULL", "c\"d"]])
+        );
+        assert_eq!(decode_text(int4_array, "{1,2"), json!("{1,2"));
+    }
+
+    #[test]
+    fn binary_scalars() {
+        assert_eq!(binary("bool", &[1]), json!(true));
+        assert_eq!(binary("int2", &(-2i16).to_be_bytes()), json!(-2));
+        assert_eq!(binary("int4", &7i32.to_be_bytes()), json!(7));
+        assert_eq!(binary("int8", &i64::MAX.to_be_bytes()), json!(i64::MAX));
+        assert_eq!(binary("float4", &0.1f32.to_be_bytes()), json!(0.1));
+        assert_eq!(binary("float8", &f64::NAN.to_be_bytes()), json!("NaN"));
+        assert_eq!(
+            binary("float8", &f64::INFINITY.to_be_bytes()),
+            json!("Infinity")
+        );
+        assert_eq!(binary("text", "héllo".as_bytes()), json!("héllo"));
+        assert_eq!(binary("bpchar", b"ab  "), json!("ab  "));
+        assert_eq!(binary("char", b"x"), json!("x"));
+        assert_eq!(binary("bytea", &[10, 11]), json!("Cgs="));
+        assert_eq!(binary("json", br#"{"a":[1]}"#), json!({"a": [1]}));
+        assert_eq!(binary("jsonb", b"\x01[true]"), json!([true]));
+        assert_eq!(
+            binary(
+                "uuid",
+                &[
+                    0xa0, 0xee, 0xbc, 0x99, 0x9c, 0x0b, 0x4e, 0xf8, 0xbb, 0x6d, 0x6b, 0xb9, 0xbd,
+                    0x38, 0x0a, 0x11
+                ]
+            ),
+            json!("a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11")
+        );
+    }
+
+    #[test]
+    fn binary_numeric() {
+        assert_eq!(
+            binary("numeric", &numeric(0, NUMERIC_NEG, 4, &[12, 3400])),
+            json!("-12.3400")
+        );
+        assert_eq!(binary("numeric", &numeric(-1, 0, 4, &[1])), json!("0.0001"));
+        assert_eq!(binary("numeric", &numeric(1, 0, 0, &[1])), json!("10000"));
+        assert_eq!(binary("numeric", &numeric(0, 0, 0, &[])), json!("0"));
+        assert_eq!(
+            binary("numeric", &numeric(0, NUMERIC_NAN, 0, &[])),
+            json!("NaN")
+        );
+        assert_eq!(
+            binary("numeric", &numeric(0, NUMERIC_PINF, 0, &[])),
+            json!("Infinity")
+        );
+        assert_eq!(
+            binary("numeric", &numeric(0, NUMERIC_NINF, 0, &[])),
+            json!("-Infinity")
+        );
+    }
+
+    #[test]
+    fn binary_dates_and_times() {
+        assert_eq!(binary("date", &0i32.to_be_bytes()), json!("2000-01-01"));
+        assert_eq!(binary("date", &(-1i32).to_be_bytes()), json!("1999-12-31"));
+        assert_eq!(binary("date", &i32::MAX.to_be_bytes()), json!("infinity"));
+        assert_eq!(binary("date", &i32::MIN.to_be_bytes()), json!("-infinity"));
+        // 1 BC is year 0 of the proleptic Gregorian calendar
+        assert_eq!(
+            binary("date", &(-730_120i32).to_be_bytes()),
+            json!("0001-12-31 BC")
+        );
+
+        let micros = 3_723_000_500i64;
+        assert_eq!(
+            binary("time", &micros.to_be_bytes()),
+            json!("01:02:03.0005")
+        );
+        assert_eq!(
+            binary("timestamp", &(MICROS_PER_DAY + micros).to_be_bytes()),
+            json!("2000-01-02T01:02:03.0005")
+        );
+        assert_eq!(
+            binary("timestamptz", &(-1i64).to_be_bytes()),
+            json!("1999-12-31T23:59:59.999999+00:00")
+        );
+        assert_eq!(
+            binary("timestamp", &i64::MAX.to_be_bytes()),
+            json!("infinity")
+        );
+        assert_eq!(
+            binary("timestamptz", &i64::MIN.to_be_bytes()),
+            json!("-infinity")
+        );
+
+        let timetz =
+            |micros: i64, zone: i32| [&micros.to_be_bytes()[..], &zone.to_be_bytes()].concat();
+        assert_eq!(
+            binary("timetz", &timetz(micros, -7200)),
+            json!("01:02:03.0005+02")
+        );
+        assert_eq!(
+            binary("timetz", &timetz(0, 19_800)),
+            json!("00:00:00-05:30")
+        );
+        assert_eq!(binary("timetz", &timetz(0, 0)), json!("00:00:00+00"));
+    }
+
+    #[test]
+    fn binary_intervals() {
+        let hour = 3_600 * MICROS_PER_SECOND;
+        assert_eq!(
+            binary(
+                "interval",
+                &interval(4 * hour + 306 * MICROS_PER_SECOND + 500_000, 3, 14)
+            ),
+            json!("1 year 2 mons 3 days 04:05:06.5")
+        );
+        assert_eq!(binary("interval", &interval(0, 0, 0)), json!("00:00:00"));
+        assert_eq!(
+            binary("interval", &interval(2 * hour, -1, 0)),
+            json!("-1 days +02:00:00")
+        );
+        assert_eq!(
+            binary("interval", &interval(-hour, 1, 0)),
+            json!("1 day -01:00:00")
+        );
+        assert_eq!(binary("interval", &interval(0, 0, -10)), json!("-10 mons"));
+        assert_eq!(
+            binary("interval", &interval(0, -1, 12)),
+            json!("1 year -1 days")
+        );
+        assert_eq!(
+            binary("interval", &interval(100 * hour, 0, 0)),
+            json!("100:00:00")
+        );
+        assert_eq!(
+            binary("interval", &interval(-1_250_000, 0, 0)),
+            json!("-00:00:01.25")
+        );
+        assert_eq!(
+            binary("interval", &interval(i64::MAX, i32::MAX, i32::MAX)),
+            json!("infinity")
+        );
+    }
+
+    #[test]
+    fn binary_network_addresses() {
+        assert_eq!(
+            binary("inet", &[2, 32, 0, 4, 192, 168, 1, 5]),
+            json!("192.168.1.5")
+        );
+        assert_eq!(
+            binary("inet", &[2, 24, 0, 4, 192, 168, 1, 5]),
+            json!("192.168.1.5/24")
+        );
+        assert_eq!(
+            binary("cidr", &[2, 8, 1, 4, 10, 0, 0, 0]),
+            json!("10.0.0.0/8")
+        );
+        let mut ipv6 = vec![3, 128, 0, 16];
+        ipv6.extend([0; 15]);
+        ipv6.push(1);
+        assert_eq!(binary("inet", &ipv6), json!("::1"));
+        assert_eq!(
+            binary("macaddr", &[8, 0, 0x2b, 1, 2, 3]),
+            json!("08:00:2b:01:02:03")
+        );
+    }
+
+    #[test]
+    fn binary_arrays() {
+        let one = 1i32.to_be_bytes();
+        let three = 3i32.to_be_bytes();
+        assert_eq!(
+            binary(
+                "_int4",
+                &array(Type::INT4.oid(), &[Some(&one), None, Some(&three)])
+            ),
+            json!([1, null, 3])
+        );
+        assert_eq!(
+            binary("_text", &array(Type::TEXT.oid(), &[None, Some(b"b")])),
+            json!([null, "b"])
+        );
+
+        // No dimensions for an empty array
+        let mut empty = vec![0; 8];
+        empty.extend(Type::INT4.oid().to_be_bytes());
+        assert_eq!(binary("_int4", &empty), json!([]));
+
+        // Two dimensions, {{1,NULL},{3,1}}
+        let mut nested = Vec::new();
+        nested.extend(2i32.to_be_bytes());
+        nested.extend(1i32.to_be_bytes());
+        nested.extend(Type::INT4.oid().to_be_bytes());
+        for _ in 0..2 {
+            nested.extend(2i32.to_be_bytes());
+            nested.extend(1i32.to_be_bytes());
+        }
+        for element in [Some(&one), None, Some(&three), Some(&one)] {
+            match element {
+                Some(element) => {
+                    nested.extend(4i32.to_be_bytes());
+                    nested.extend(element);
+                }
+                None => nested.extend((-1i32).to_be_bytes()),
+            }
+        }
+        assert_eq!(binary("_int4", &nested), json!([[1, null], [3, 1]]));
+    }
+
+    #[test]
+    fn binary_enums_and_domains_decode_as_their_resolved_type() {
+        // An enum resolves to text, its binary format is the label
+        assert_eq!(decode_binary(Type::TEXT.oid(), b"happy"), json!("happy"));
+        // Arrays of enums keep the enum's OID in the header
+        assert_eq!(
+            decode_binary(
+                Type::TEXT_ARRAY.oid(),
+                &array(999_999, &[Some(b"sad"), Some(b"happy")])
+            ),
+            json!(["sad", "happy"])
+        );
+        // A domain over int4 resolves to int4, in text and binary format alike
+        assert_eq!(
+            decode_binary(Type::INT4.oid(), &7i32.to_be_bytes()),
+            decode_text(Type::INT4.oid(), "7")
+        );
+    }
+
+    #[test]
+    fn binary_without_native_decoding_is_tagged() {
+        // money would otherwise be an 8 byte integer that happens to be valid UTF-8
+        let cents = 1250i64.to_be_bytes();
+        assert_eq!(
+            binary("money", &cents),
+            json!({"type_oid": Type::MONEY.oid(), "binary": BASE64.encode(cents)})
+        );
+        // Types unknown to walstream that aren't enums or domains, e.g. composite types, are not
+        // guessed to be text either
+        assert_eq!(
+            decode_binary(999_999, b"happy"),
+            json!({"type_oid": 999_999, "binary": "aGFwcHk="})
+        );
+        // Malformed values of natively decoded types as well
+        assert_eq!(
+            binary("int4", &[1, 2]),
+            json!({"type_oid": Type::INT4.oid(), "binary": "AQI="})
+        );
+        assert_eq!(
+            binary("text", &[0xff]),
+            json!({"type_oid": Type::TEXT.oid(), "binary": "/w=="})
+        );
+    }
+}
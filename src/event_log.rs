@@ -0,0 +1,647 @@
+use crate::grpc::plugin::{ServerMessage, server_message::Msg};
+use prost::Message;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tokio_postgres::types::PgLsn;
+use tracing::{info, warn};
+
+/// Length and LSN preceding every event in a segment
+const RECORD_HEADER_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy)]
+pub struct EventLogOptions {
+    /// Segments are closed at the next transaction boundary after reaching this size
+    pub segment_size: u64,
+    pub retention_bytes: u64,
+    pub retention: Duration,
+}
+
+/// Append-only log of the events published by the replication stream, split into segment
+/// files named `<sequence>-<start LSN>.log`. Clients read it at their own position, so a slow
+/// or disconnected client never loses events that are still retained.
+///
+/// Segments only end at transaction boundaries, and readers only see events up to the last
+/// boundary, so a transaction cut short by a restart can be truncated and streamed again.
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    writer: Arc<Mutex<LogWriter>>,
+    end: watch::Sender<LogPosition>,
+}
+
+/// Position after the last transaction boundary written to the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct LogPosition {
+    segment: u64,
+    offset: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Segment {
+    sequence: u64,
+    /// LSN of the last transaction boundary before the segment
+    start_lsn: PgLsn,
+    path: PathBuf,
+    size: u64,
+}
+
+#[derive(Debug)]
+struct LogWriter {
+    dir: PathBuf,
+    options: EventLogOptions,
+    /// All retained segments, the last one is written to
+    segments: VecDeque<Segment>,
+    file: File,
+    in_transaction: bool,
+    boundary_offset: u64,
+    boundary_lsn: PgLsn,
+    /// Set when a write failed, the log must be recovered before it is synced again
+    failed: bool,
+}
+
+impl EventLog {
+    /// Open the log in `dir`, dropping a transaction left incomplete by the previous run
+    pub fn open(dir: &Path, options: EventLogOptions) -> Result<Self, Box<dyn Error>> {
+        std::fs::create_dir_all(dir)?;
+
+        let mut segments = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if let Some(segment) = Segment::from_path(&path)? {
+                segments.push(segment);
+            }
+        }
+        segments.sort_by_key(|segment| segment.sequence);
+        let mut segments = VecDeque::from(segments);
+        if segments.is_empty() {
+            segments.push_back(Segment::create(dir, 0, PgLsn::from(0))?);
+        }
+
+        let active = segments.back_mut().unwrap();
+        let (boundary_offset, boundary_lsn) = scan_boundaries(&active.path, active.start_lsn)?;
+        let file = OpenOptions::new().append(true).open(&active.path)?;
+        if active.size > boundary_offset {
+            warn!(
+                "Truncating incomplete transaction at the end of {}",
+                active.path.display()
+            );
+            file.set_len(boundary_offset)?;
+            active.size = boundary_offset;
+        }
+        let active_sequence = active.sequence;
+
+        info!(
+            "Opened event log {} with {} segments, last boundary at {}",
+            dir.display(),
+            segments.len(),
+            boundary_lsn
+        );
+
+        let (end, _) = watch::channel(LogPosition {
+            segment: active_sequence,
+            offset: boundary_offset,
+        });
+        Ok(Self {
+            writer: Arc::new(Mutex::new(LogWriter {
+                dir: dir.to_path_buf(),
+                options,
+                segments,
+                file,
+                in_transaction: false,
+                boundary_offset,
+                boundary_lsn,
+                failed: false,
+            })),
+            end,
+        })
+    }
+
+    pub fn append(&self, pg_lsn: u64, event: &ServerMessage) -> std::io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        if writer.failed {
+            return Err(std::io::Error::other(
+                "event log needs recovery after a failed write",
+            ));
+        }
+
+        let payload = event.encode_to_vec();
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&pg_lsn.to_le_bytes());
+        record.extend_from_slice(&payload);
+        if let Err(e) = writer.file.write_all(&record) {
+            writer.failed = true;
+            return Err(e);
+        }
+        writer.segments.back_mut().unwrap().size += record.len() as u64;
+
+        let Some(msg) = &event.msg else {
+            return Ok(());
+        };
+        if is_boundary(&mut writer.in_transaction, msg) {
+            writer.boundary_offset = writer.segments.back().unwrap().size;
+            writer.boundary_lsn = writer.boundary_lsn.max(PgLsn::from(pg_lsn));
+            if writer.boundary_offset >= writer.options.segment_size
+                && let Err(e) = writer.rotate()
+            {
+                writer.failed = true;
+                return Err(e);
+            }
+
+            let active = writer.segments.back().unwrap();
+            self.end.send_replace(LogPosition {
+                segment: active.sequence,
+                offset: writer.boundary_offset,
+            });
+        }
+        Ok(())
+    }
+
+    /// Flush the log to disk and drop expired segments. Returns the LSN of the last transaction
+    /// boundary, which the replication slot can be advanced to.
+    pub fn sync(&self) -> std::io::Result<PgLsn> {
+        let mut writer = self.writer.lock().unwrap();
+        if writer.failed {
+            return Err(std::io::Error::other(
+                "event log needs recovery after a failed write",
+            ));
+        }
+        writer.file.sync_data()?;
+        writer.apply_retention();
+        Ok(writer.boundary_lsn)
+    }
+
+    /// Drop the events of an incomplete transaction, before the replication stream restarts
+    /// after the last transaction boundary. Returns the LSN of that boundary.
+    pub fn recover(&self) -> std::io::Result<PgLsn> {
+        let mut writer = self.writer.lock().unwrap();
+        let writer = &mut *writer;
+        let boundary_offset = writer.boundary_offset;
+        let active = writer.segments.back_mut().unwrap();
+        if active.size > boundary_offset {
+            active.size = boundary_offset;
+            writer.file.set_len(boundary_offset)?;
+        }
+        writer.in_transaction = false;
+        writer.failed = false;
+        Ok(writer.boundary_lsn)
+    }
+
+    /// Reader positioned after the last event in the log
+    pub fn reader(&self) -> LogReader {
+        let end = self.end.subscribe();
+        let position = *end.borrow();
+        LogReader {
+            log: self.clone(),
+            end,
+            segment: position.segment,
+            offset: position.offset,
+            file: None,
+        }
+    }
+
+    /// First retained segment starting from `sequence`
+    fn segment_from(&self, sequence: u64) -> Option<Segment> {
+        let writer = self.writer.lock().unwrap();
+        writer
+            .segments
+            .iter()
+            .find(|segment| segment.sequence >= sequence)
+            .cloned()
+    }
+
+    /// Last segment starting at or before `lsn`, every transaction committed after `lsn` is in it
+    /// or a later segment. Returns `None` if older segments were already removed.
+    fn segment_for(&self, lsn: PgLsn) -> Option<Segment> {
+        let writer = self.writer.lock().unwrap();
+        writer
+            .segments
+            .iter()
+            .rev()
+            .find(|segment| segment.start_lsn <= lsn)
+            .cloned()
+    }
+
+    /// LSN of the last transaction boundary before the oldest retained event, readers can seek
+    /// to any LSN from it
+    pub fn first_lsn(&self) -> PgLsn {
+        self.first_segment().start_lsn
+    }
+
+    fn first_segment(&self) -> Segment {
+        let writer = self.writer.lock().unwrap();
+        writer.segments.front().cloned().unwrap()
+    }
+}
+
+impl LogWriter {
+    /// Close the active segment and continue in a new one
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.sync_data()?;
+
+        let sequence = self.segments.back().unwrap().sequence + 1;
+        let segment = Segment::create(&self.dir, sequence, self.boundary_lsn)?;
+        self.file = OpenOptions::new().append(true).open(&segment.path)?;
+        self.segments.push_back(segment);
+        self.boundary_offset = 0;
+
+        self.apply_retention();
+        Ok(())
+    }
+
+    /// Remove the oldest closed segments beyond the retained size or age
+    fn apply_retention(&mut self) {
+        let now = SystemTime::now();
+        let mut total_size: u64 = self.segments.iter().map(|segment| segment.size).sum();
+        while self.segments.len() > 1 {
+            let oldest = self.segments.front().unwrap();
+            let expired = std::fs::metadata(&oldest.path)
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| {
+                    now.duration_since(modified).unwrap_or_default() > self.options.retention
+                });
+            if total_size <= self.options.retention_bytes && !expired {
+                break;
+            }
+
+            info!("Removing event log segment {}", oldest.path.display());
+            if let Err(e) = std::fs::remove_file(&oldest.path) {
+                warn!("Failed to remove {}: {}", oldest.path.display(), e);
+                break;
+            }
+            total_size -= oldest.size;
+            self.segments.pop_front();
+        }
+    }
+}
+
+impl Segment {
+    fn create(dir: &Path, sequence: u64, start_lsn: PgLsn) -> std::io::Result<Self> {
+        let path = dir.join(format!(
+            "{:020}-{:016X}.log",
+            sequence,
+            u64::from(start_lsn)
+        ));
+        File::create(&path)?;
+        Ok(Self {
+            sequence,
+            start_lsn,
+            path,
+            size: 0,
+        })
+    }
+
+    fn from_path(path: &Path) -> std::io::Result<Option<Self>> {
+        if path.extension().is_none_or(|extension| extension != "log") {
+            return Ok(None);
+        }
+        let Some((sequence, start_lsn)) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.split_once('-'))
+        else {
+            return Ok(None);
+        };
+        let (Ok(sequence), Ok(start_lsn)) =
+            (sequence.parse::<u64>(), u64::from_str_radix(start_lsn, 16))
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            sequence,
+            start_lsn: PgLsn::from(start_lsn),
+            path: path.to_path_buf(),
+            size: std::fs::metadata(path)?.len(),
+        }))
+    }
+}
+
+/// Reads the log from a position, waiting for new events at its end
+#[derive(Debug)]
+pub struct LogReader {
+    log: EventLog,
+    end: watch::Receiver<LogPosition>,
+    segment: u64,
+    offset: u64,
+    file: Option<BufReader<File>>,
+}
+
+impl LogReader {
+    /// Move to the segment holding the transactions committed after `lsn`. Earlier transactions
+    /// in that segment are read too. Returns false if the log no longer reaches back to `lsn`.
+    pub fn seek(&mut self, lsn: PgLsn) -> bool {
+        let (segment, complete) = match self.log.segment_for(lsn) {
+            Some(segment) => (segment, true),
+            None => (self.log.first_segment(), false),
+        };
+        self.segment = segment.sequence;
+        self.offset = 0;
+        self.file = None;
+        complete
+    }
+
+    pub async fn next(&mut self) -> std::io::Result<ServerMessage> {
+        loop {
+            let end = *self.end.borrow_and_update();
+            let position = LogPosition {
+                segment: self.segment,
+                offset: self.offset,
+            };
+            if position < end {
+                if let Some(event) = self.read_event()? {
+                    return Ok(event);
+                }
+                // End of a closed segment
+                self.segment += 1;
+                self.offset = 0;
+                self.file = None;
+                continue;
+            }
+
+            if self.end.changed().await.is_err() {
+                return Err(std::io::Error::other("event log closed"));
+            }
+        }
+    }
+
+    fn read_event(&mut self) -> std::io::Result<Option<ServerMessage>> {
+        if self.file.is_none() {
+            self.open()?;
+        }
+        let file = self.file.as_mut().unwrap();
+
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        match file.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let mut payload = vec![0u8; len];
+        file.read_exact(&mut payload)?;
+        self.offset += (RECORD_HEADER_LEN + len) as u64;
+
+        Ok(Some(ServerMessage::decode(payload.as_slice())?))
+    }
+
+    /// Open the current segment, skipping ahead if it was removed by retention
+    fn open(&mut self) -> std::io::Result<()> {
+        loop {
+            let Some(segment) = self.log.segment_from(self.segment) else {
+                return Err(std::io::Error::other(format!(
+                    "event log segment {} not found",
+                    self.segment
+                )));
+            };
+            if segment.sequence != self.segment {
+                warn!(
+                    "Event log segments {}..{} were removed before being read",
+                    self.segment, segment.sequence
+                );
+                self.segment = segment.sequence;
+                self.offset = 0;
+            }
+
+            match File::open(&segment.path) {
+                Ok(mut file) => {
+                    file.seek(SeekFrom::Start(self.offset))?;
+                    self.file = Some(BufReader::new(file));
+                    return Ok(());
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => self.segment += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Whether the event ends a transaction, or is sent outside of one
+fn is_boundary(in_transaction: &mut bool, msg: &Msg) -> bool {
+    match msg {
+        Msg::Begin(_) | Msg::BeginPrepare(_) => {
+            *in_transaction = true;
+            false
+        }
+        Msg::Commit(_) | Msg::Prepare(_) => std::mem::take(in_transaction),
+        _ => !*in_transaction,
+    }
+}
+
+/// Offset and LSN of the last transaction boundary in a segment, read record by record
+fn scan_boundaries(path: &Path, start_lsn: PgLsn) -> std::io::Result<(u64, PgLsn)> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut in_transaction = false;
+    let mut boundary = (0, start_lsn);
+    let mut offset = 0;
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    let mut payload = Vec::new();
+    // A record cut short by a crash ends the scan, like one that does not decode
+    while offset + RECORD_HEADER_LEN as u64 <= size {
+        reader.read_exact(&mut header)?;
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+        let pg_lsn = u64::from_le_bytes(header[4..].try_into().unwrap());
+        if offset + RECORD_HEADER_LEN as u64 + len > size {
+            break;
+        }
+        payload.resize(len as usize, 0);
+        reader.read_exact(&mut payload)?;
+        offset += RECORD_HEADER_LEN as u64 + len;
+
+        let Ok(ServerMessage { msg: Some(msg), .. }) = ServerMessage::decode(payload.as_slice())
+        else {
+            break;
+        };
+        if is_boundary(&mut in_transaction, &msg) {
+            boundary = (offset, boundary.1.max(PgLsn::from(pg_lsn)));
+        }
+    }
+    Ok(boundary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::plugin::{BeginEvent, CommitEvent, InsertEvent, LogicalMessageEvent};
+
+    /// Empty directory for a test, removed again when dropped
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "walstream-event-log-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn options() -> EventLogOptions {
+        EventLogOptions {
+            segment_size: 1 << 20,
+            retention_bytes: u64::MAX,
+            retention: Duration::from_secs(3600),
+        }
+    }
+
+    fn event(msg: Msg) -> ServerMessage {
+        ServerMessage {
+            msg: Some(msg),
+            ..Default::default()
+        }
+    }
+
+    /// Append a transaction, leaving out its commit unless `committed`
+    fn append_transaction(log: &EventLog, lsn: u64, committed: bool) {
+        log.append(lsn, &event(Msg::Begin(BeginEvent::default())))
+            .unwrap();
+        log.append(lsn, &event(Msg::Insert(InsertEvent::default())))
+            .unwrap();
+        if committed {
+            log.append(lsn, &event(Msg::Commit(CommitEvent::default())))
+                .unwrap();
+        }
+    }
+
+    fn active_path(log: &EventLog) -> PathBuf {
+        log.writer
+            .lock()
+            .unwrap()
+            .segments
+            .back()
+            .unwrap()
+            .path
+            .clone()
+    }
+
+    /// Events up to the end of the log, read from its start
+    async fn read_all(log: &EventLog) -> Vec<ServerMessage> {
+        let mut reader = log.reader();
+        reader.seek(PgLsn::from(0));
+        let mut events = Vec::new();
+        while let Ok(event) = tokio::time::timeout(Duration::from_millis(50), reader.next()).await {
+            events.push(event.unwrap());
+        }
+        events
+    }
+
+    #[test]
+    fn scan_finds_the_last_boundary() {
+        let dir = TestDir::new("scan");
+        let log = EventLog::open(&dir.0, options()).unwrap();
+        let path = active_path(&log);
+        assert_eq!(
+            scan_boundaries(&path, PgLsn::from(5)).unwrap(),
+            (0, PgLsn::from(5))
+        );
+
+        append_transaction(&log, 10, true);
+        let committed = std::fs::metadata(&path).unwrap().len();
+        // Events outside of a transaction are boundaries of their own
+        log.append(
+            20,
+            &event(Msg::LogicalMessage(LogicalMessageEvent::default())),
+        )
+        .unwrap();
+        let message = std::fs::metadata(&path).unwrap().len();
+        assert!(message > committed);
+        append_transaction(&log, 30, false);
+
+        assert_eq!(
+            scan_boundaries(&path, PgLsn::from(0)).unwrap(),
+            (message, PgLsn::from(20))
+        );
+    }
+
+    #[test]
+    fn scan_stops_at_a_torn_record() {
+        let dir = TestDir::new("torn");
+        let log = EventLog::open(&dir.0, options()).unwrap();
+        let path = active_path(&log);
+        append_transaction(&log, 10, true);
+        let committed = std::fs::metadata(&path).unwrap().len();
+
+        // Header of a record whose payload was never written
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&1000u32.to_le_bytes()).unwrap();
+        file.write_all(&40u64.to_le_bytes()).unwrap();
+        file.write_all(b"partial").unwrap();
+        assert_eq!(
+            scan_boundaries(&path, PgLsn::from(0)).unwrap(),
+            (committed, PgLsn::from(10))
+        );
+
+        // Half a header
+        file.set_len(committed + 5).unwrap();
+        assert_eq!(
+            scan_boundaries(&path, PgLsn::from(0)).unwrap(),
+            (committed, PgLsn::from(10))
+        );
+    }
+
+    #[tokio::test]
+    async fn open_truncates_an_incomplete_transaction() {
+        let dir = TestDir::new("reopen");
+        {
+            let log = EventLog::open(&dir.0, options()).unwrap();
+            append_transaction(&log, 10, true);
+            append_transaction(&log, 20, false);
+            log.sync().unwrap();
+        }
+
+        let log = EventLog::open(&dir.0, options()).unwrap();
+        assert_eq!(log.recover().unwrap(), PgLsn::from(10));
+        assert_eq!(read_all(&log).await.len(), 3);
+
+        append_transaction(&log, 20, true);
+        assert_eq!(read_all(&log).await.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn recover_drops_the_events_after_the_last_boundary() {
+        let dir = TestDir::new("recover");
+        let log = EventLog::open(&dir.0, options()).unwrap();
+        append_transaction(&log, 10, true);
+        let committed = std::fs::metadata(active_path(&log)).unwrap().len();
+        append_transaction(&log, 20, false);
+
+        // A failed write blocks the log until it is recovered
+        log.writer.lock().unwrap().failed = true;
+        assert!(
+            log.append(20, &event(Msg::Commit(CommitEvent::default())))
+                .is_err()
+        );
+        assert!(log.sync().is_err());
+
+        assert_eq!(log.recover().unwrap(), PgLsn::from(10));
+        assert_eq!(
+            std::fs::metadata(active_path(&log)).unwrap().len(),
+            committed
+        );
+        assert_eq!(log.sync().unwrap(), PgLsn::from(10));
+
+        // The transaction is streamed again from its begin
+        append_transaction(&log, 20, true);
+        assert_eq!(log.sync().unwrap(), PgLsn::from(20));
+        let events = read_all(&log).await;
+        assert_eq!(events.len(), 6);
+        assert!(matches!(events[3].msg, Some(Msg::Begin(_))));
+    }
+}
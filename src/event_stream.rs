@@ -1,27 +1,517 @@
+use crate::event_log::{EventLog, EventLogOptions};
 use crate::grpc::plugin::ServerMessage;
-use tokio::sync::{broadcast, watch};
+use crate::snapshot::SnapshotTable;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, broadcast, watch};
 use tokio_postgres::types::PgLsn;
+use tracing::{error, info};
 
+/// Counter for unique session ids within this process
+static SESSION_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Events the broadcast channel holds for the slowest client before it starts lagging
+pub const EVENT_BUFFER_CAPACITY: usize = 1024;
+
+/// File in the data directory holding the cursors of named consumers
+const CONSUMERS_FILE: &str = "consumers.json";
+
+/// Minimum time between two writes of the consumer cursors while acknowledgements arrive
+const CONSUMERS_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Events of one source database, streamed from its replication slot
 #[derive(Debug, Clone)]
 pub struct EventStream {
+    /// Name of the source, sent with every event
+    pub source: String,
+
     pub tx: tokio::sync::broadcast::Sender<ServerMessage>,
 
     pub last_received_lsn: tokio::sync::watch::Sender<PgLsn>, // = last WAL received
     pub last_sent_lsn: tokio::sync::watch::Sender<PgLsn>,     // = last WAL sent to gRPC
     pub last_ack_lsn: tokio::sync::watch::Sender<PgLsn>,      // = last WAL the gRPC client acked
+    pub resume_lsn: tokio::sync::watch::Sender<PgLsn>,        // = WAL the replication resumed from
+    pub replay_lsn: tokio::sync::watch::Sender<PgLsn>,        // = consumer cursor to replay from
+
+    pub snapshot_requests: tokio::sync::broadcast::Sender<RequestedSnapshot>,
+
+    /// Signalled whenever a session received an event from the broadcast channel, a paused
+    /// stream in lossless mode waits for it to resume as soon as there is room
+    pub received: Arc<Notify>,
+
+    /// Acknowledged LSN of every session and named consumer, `last_ack_lsn` follows their minimum
+    acks: Arc<Mutex<Acks>>,
+
+    /// Durable log the events are read from instead of the broadcast channel, with `--log-dir`
+    log: Arc<OnceLock<EventLog>>,
+}
+
+/// Incremental snapshot requested through gRPC, identified by the id of its signal
+#[derive(Debug, Clone)]
+pub struct RequestedSnapshot {
+    pub signal_id: String,
+    pub target: SnapshotTable,
+}
+
+#[derive(Debug, Default)]
+struct Acks {
+    /// Sessions without a consumer id, forgotten when they disconnect
+    sessions: HashMap<u64, PgLsn>,
+    /// Named consumers, kept while they are disconnected
+    consumers: HashMap<String, Consumer>,
+    /// Cursor file of the consumers, only with `--data-dir`
+    path: Option<PathBuf>,
+    saved_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Consumer {
+    acked: PgLsn,
+    /// Cursor written to the data directory, the slot is never advanced past it
+    saved: PgLsn,
+    connected: bool,
 }
 
 impl EventStream {
-    pub fn new() -> Self {
-        let (tx, _) = broadcast::channel::<ServerMessage>(1024);
+    pub fn new(source: &str) -> Self {
+        let (tx, _) = broadcast::channel::<ServerMessage>(EVENT_BUFFER_CAPACITY);
         let (last_received_lsn, _) = watch::channel(PgLsn::from(0));
         let (last_sent_lsn, _) = watch::channel(PgLsn::from(0));
         let (last_ack_lsn, _) = watch::channel(PgLsn::from(0));
+        let (resume_lsn, _) = watch::channel(PgLsn::from(0));
+        let (replay_lsn, _) = watch::channel(PgLsn::from(0));
+        let (snapshot_requests, _) = broadcast::channel::<RequestedSnapshot>(16);
         Self {
+            source: source.to_string(),
             tx: tx,
             last_received_lsn,
             last_sent_lsn,
             last_ack_lsn,
+            resume_lsn,
+            replay_lsn,
+            snapshot_requests,
+            received: Arc::new(Notify::new()),
+            acks: Arc::new(Mutex::new(Acks::default())),
+            log: Arc::new(OnceLock::new()),
         }
     }
+
+    /// Publish events through a durable log in `log_dir` instead of the broadcast channel
+    pub fn open_log(&self, log_dir: &Path, options: EventLogOptions) -> Result<(), Box<dyn Error>> {
+        self.log
+            .set(EventLog::open(log_dir, options)?)
+            .map_err(|_| "Event log is already open".into())
+    }
+
+    pub fn log(&self) -> Option<&EventLog> {
+        self.log.get()
+    }
+
+    /// Persist the cursors of named consumers in `data_dir`, restoring the ones saved by an
+    /// earlier run
+    pub fn load_consumers(&self, data_dir: &Path) -> Result<(), Box<dyn Error>> {
+        std::fs::create_dir_all(data_dir)?;
+        let path = data_dir.join(CONSUMERS_FILE);
+
+        let mut acks = self.acks.lock().unwrap();
+        if path.exists() {
+            let cursors: HashMap<String, String> =
+                serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+            for (consumer_id, lsn) in cursors {
+                let lsn = lsn
+                    .parse::<PgLsn>()
+                    .map_err(|_| format!("Invalid LSN '{}' in {}", lsn, path.display()))?;
+                acks.consumers.insert(
+                    consumer_id,
+                    Consumer {
+                        acked: lsn,
+                        saved: lsn,
+                        connected: false,
+                    },
+                );
+            }
+            info!(
+                "Loaded {} consumer cursors from {}",
+                acks.consumers.len(),
+                path.display()
+            );
+        }
+        acks.path = Some(path);
+        Ok(())
+    }
+
+    /// Track the acknowledgements of a new session, starting from the current minimum
+    pub fn register_session(&self) -> u64 {
+        let session_id = SESSION_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let last_ack_lsn = *self.last_ack_lsn.borrow();
+        self.acks
+            .lock()
+            .unwrap()
+            .sessions
+            .insert(session_id, last_ack_lsn);
+        session_id
+    }
+
+    pub fn ack_session(&self, session_id: u64, pg_lsn: PgLsn) {
+        let mut acks = self.acks.lock().unwrap();
+        if let Some(acked) = acks.sessions.get_mut(&session_id) {
+            *acked = (*acked).max(pg_lsn);
+        }
+        self.update_last_ack_lsn(&acks);
+    }
+
+    /// Continue a session after an LSN the client keeps track of itself. Changes after it are
+    /// read again from the event log or by restarting the replication. Returns the earliest
+    /// LSN the session can resume from if `pg_lsn` is before it.
+    pub fn resume_session(&self, session_id: u64, pg_lsn: PgLsn) -> Result<(), PgLsn> {
+        let mut acks = self.acks.lock().unwrap();
+        let earliest_lsn = self.earliest_resume_lsn();
+        if pg_lsn < earliest_lsn {
+            return Err(earliest_lsn);
+        }
+
+        if let Some(acked) = acks.sessions.get_mut(&session_id) {
+            *acked = pg_lsn;
+        }
+        self.update_last_ack_lsn(&acks);
+
+        if pg_lsn < *self.last_sent_lsn.borrow() {
+            self.replay_lsn.send(pg_lsn).ok();
+        }
+        Ok(())
+    }
+
+    /// Earliest LSN a session can resume from: the start of the event log, the acknowledged
+    /// position of a permanent slot the replication can restart from, or otherwise the last
+    /// event already streamed
+    fn earliest_resume_lsn(&self) -> PgLsn {
+        match self.log() {
+            Some(log) => log.first_lsn(),
+            // The replication only listens for replays with a permanent slot
+            None if self.replay_lsn.receiver_count() > 0 => *self.last_ack_lsn.borrow(),
+            None => (*self.last_sent_lsn.borrow()).max(*self.last_ack_lsn.borrow()),
+        }
+    }
+
+    /// Stop waiting for the acknowledgements of a disconnected session
+    pub fn unregister_session(&self, session_id: u64) {
+        let mut acks = self.acks.lock().unwrap();
+        acks.sessions.remove(&session_id);
+        self.update_last_ack_lsn(&acks);
+    }
+
+    /// Continue a session as the named consumer, returning its cursor. A new consumer starts
+    /// at the current stream position. Returns `None` if the consumer is already connected.
+    pub fn attach_consumer(&self, session_id: u64, consumer_id: &str) -> Option<PgLsn> {
+        let mut acks = self.acks.lock().unwrap();
+        let last_sent_lsn = *self.last_sent_lsn.borrow();
+        let session_ack = acks.sessions.get(&session_id).copied();
+
+        let consumer = acks
+            .consumers
+            .entry(consumer_id.to_string())
+            .or_insert_with(|| {
+                let lsn = session_ack.map_or(last_sent_lsn, |lsn| lsn.max(last_sent_lsn));
+                Consumer {
+                    acked: lsn,
+                    saved: PgLsn::from(0),
+                    connected: false,
+                }
+            });
+        if consumer.connected {
+            return None;
+        }
+        consumer.connected = true;
+        let cursor = consumer.acked;
+
+        acks.sessions.remove(&session_id);
+        self.save_consumers(&mut acks, true);
+        self.update_last_ack_lsn(&acks);
+
+        // Changes after the cursor were already streamed, the replication restarts from the
+        // minimum acknowledgement to send them again
+        if cursor < last_sent_lsn {
+            self.replay_lsn.send(cursor).ok();
+        }
+        Some(cursor)
+    }
+
+    pub fn ack_consumer(&self, consumer_id: &str, pg_lsn: PgLsn) {
+        let mut acks = self.acks.lock().unwrap();
+        if let Some(consumer) = acks.consumers.get_mut(consumer_id) {
+            consumer.acked = consumer.acked.max(pg_lsn);
+        }
+        self.save_consumers(&mut acks, false);
+        self.update_last_ack_lsn(&acks);
+    }
+
+    /// Keep the cursor of a disconnected consumer, it still holds back the slot
+    pub fn detach_consumer(&self, consumer_id: &str) {
+        let mut acks = self.acks.lock().unwrap();
+        if let Some(consumer) = acks.consumers.get_mut(consumer_id) {
+            consumer.connected = false;
+        }
+        self.save_consumers(&mut acks, true);
+        self.update_last_ack_lsn(&acks);
+    }
+
+    /// Write the consumer cursors to the data directory, at most once per
+    /// `CONSUMERS_SAVE_INTERVAL` unless forced. Without a data directory they only live in memory.
+    fn save_consumers(&self, acks: &mut Acks, force: bool) {
+        if acks.consumers.values().all(|c| c.saved == c.acked) {
+            return;
+        }
+
+        if let Some(path) = &acks.path {
+            if !force
+                && acks
+                    .saved_at
+                    .is_some_and(|saved_at| saved_at.elapsed() < CONSUMERS_SAVE_INTERVAL)
+            {
+                return;
+            }
+
+            let cursors: HashMap<&str, String> = acks
+                .consumers
+                .iter()
+                .map(|(consumer_id, c)| (consumer_id.as_str(), c.acked.to_string()))
+                .collect();
+            let tmp_path = path.with_extension("json.tmp");
+            let written = serde_json::to_string_pretty(&cursors)
+                .map_err(std::io::Error::from)
+                .and_then(|json| std::fs::write(&tmp_path, json))
+                .and_then(|_| std::fs::rename(&tmp_path, path));
+            if let Err(e) = written {
+                error!(
+                    "Failed to save consumer cursors to {}: {}",
+                    path.display(),
+                    e
+                );
+                return;
+            }
+            acks.saved_at = Some(Instant::now());
+        }
+
+        for consumer in acks.consumers.values_mut() {
+            consumer.saved = consumer.acked;
+        }
+    }
+
+    /// Advance `last_ack_lsn` to the minimum acknowledged by all sessions and saved for all
+    /// consumers. It never moves backwards, as the replication slot cannot be rewound.
+    fn update_last_ack_lsn(&self, acks: &Acks) {
+        let min_ack_lsn = acks
+            .sessions
+            .values()
+            .chain(acks.consumers.values().map(|c| &c.saved))
+            .min()
+            .copied();
+        if let Some(min_ack_lsn) = min_ack_lsn {
+            self.last_ack_lsn.send_if_modified(|lsn| {
+                let advanced = *lsn < min_ack_lsn;
+                if advanced {
+                    *lsn = min_ack_lsn;
+                }
+                advanced
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::plugin::server_message::Msg;
+    use crate::grpc::plugin::{BeginEvent, CommitEvent};
+
+    /// Empty directory for a test, removed again when dropped
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "walstream-event-stream-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn lsn(lsn: u64) -> PgLsn {
+        PgLsn::from(lsn)
+    }
+
+    /// Stream that sent events up to `last_sent` and had them acknowledged up to `last_ack`
+    fn stream(last_sent: u64, last_ack: u64) -> EventStream {
+        let stream = EventStream::new("test");
+        stream.last_sent_lsn.send_replace(lsn(last_sent));
+        stream.last_ack_lsn.send_replace(lsn(last_ack));
+        stream
+    }
+
+    #[test]
+    fn temporary_slots_resume_after_the_last_sent_event() {
+        let stream = stream(100, 50);
+        let session_id = stream.register_session();
+
+        // Acknowledged but already sent changes are gone with a temporary slot
+        assert_eq!(stream.resume_session(session_id, lsn(80)), Err(lsn(100)));
+        assert_eq!(stream.resume_session(session_id, lsn(100)), Ok(()));
+        assert_eq!(stream.resume_session(session_id, lsn(120)), Ok(()));
+        assert_eq!(*stream.last_ack_lsn.borrow(), lsn(120));
+    }
+
+    #[test]
+    fn permanent_slots_resume_after_the_acknowledged_position() {
+        let stream = stream(100, 50);
+        let mut replay = stream.replay_lsn.subscribe();
+        let session_id = stream.register_session();
+
+        assert_eq!(stream.resume_session(session_id, lsn(40)), Err(lsn(50)));
+        assert!(!replay.has_changed().unwrap());
+
+        // Changes after the position were sent already, the replication replays them
+        assert_eq!(stream.resume_session(session_id, lsn(60)), Ok(()));
+        assert_eq!(*replay.borrow_and_update(), lsn(60));
+
+        // Nothing to replay after the last sent event
+        assert_eq!(stream.resume_session(session_id, lsn(100)), Ok(()));
+        assert_eq!(stream.resume_session(session_id, lsn(150)), Ok(()));
+        assert!(!replay.has_changed().unwrap());
+    }
+
+    #[test]
+    fn logs_resume_after_their_first_event() {
+        let dir = TestDir::new("resume");
+        let stream = stream(300, 300);
+        let mut replay = stream.replay_lsn.subscribe();
+        // Every transaction fills a segment, and only the last one is retained
+        stream
+            .open_log(
+                &dir.0,
+                EventLogOptions {
+                    segment_size: 1,
+                    retention_bytes: 0,
+                    retention: Duration::from_secs(3600),
+                },
+            )
+            .unwrap();
+        let log = stream.log().unwrap();
+        for (lsn, msg) in [
+            (100, Msg::Begin(BeginEvent::default())),
+            (100, Msg::Commit(CommitEvent::default())),
+            (200, Msg::Begin(BeginEvent::default())),
+            (200, Msg::Commit(CommitEvent::default())),
+        ] {
+            let event = ServerMessage {
+                msg: Some(msg),
+                ..Default::default()
+            };
+            log.append(lsn, &event).unwrap();
+        }
+        assert_eq!(log.first_lsn(), lsn(200));
+
+        // The log replays acknowledged events as well, back to its first one
+        let session_id = stream.register_session();
+        assert_eq!(stream.resume_session(session_id, lsn(150)), Err(lsn(200)));
+        assert_eq!(stream.resume_session(session_id, lsn(200)), Ok(()));
+        assert_eq!(*replay.borrow_and_update(), lsn(200));
+    }
+
+    #[test]
+    fn consumer_cursors_survive_a_restart() {
+        let dir = TestDir::new("restart");
+        let stream = stream(10, 0);
+        stream.load_consumers(&dir.0).unwrap();
+        let session_id = stream.register_session();
+        assert_eq!(stream.attach_consumer(session_id, "billing"), Some(lsn(10)));
+        stream.ack_consumer("billing", lsn(80));
+        stream.detach_consumer("billing");
+
+        let restarted = EventStream::new("test");
+        restarted.load_consumers(&dir.0).unwrap();
+        let mut replay = restarted.replay_lsn.subscribe();
+        restarted.last_sent_lsn.send_replace(lsn(100));
+        let session_id = restarted.register_session();
+        assert_eq!(
+            restarted.attach_consumer(session_id, "billing"),
+            Some(lsn(80))
+        );
+        // Changes after the cursor are sent again
+        assert_eq!(*replay.borrow_and_update(), lsn(80));
+    }
+
+    #[test]
+    fn consumers_connect_once_at_a_time() {
+        let stream = stream(10, 0);
+        let first = stream.register_session();
+        let second = stream.register_session();
+        assert_eq!(stream.attach_consumer(first, "billing"), Some(lsn(10)));
+        assert_eq!(stream.attach_consumer(second, "billing"), None);
+
+        stream.ack_consumer("billing", lsn(20));
+        stream.detach_consumer("billing");
+        assert_eq!(stream.attach_consumer(second, "billing"), Some(lsn(20)));
+    }
+
+    #[test]
+    fn slot_never_advances_past_the_saved_cursor() {
+        let dir = TestDir::new("saved");
+        let stream = stream(10, 0);
+        stream.load_consumers(&dir.0).unwrap();
+        let session_id = stream.register_session();
+        stream.attach_consumer(session_id, "billing");
+        assert_eq!(*stream.last_ack_lsn.borrow(), lsn(10));
+
+        // Acknowledgements are saved at most once per interval, the slot waits for the write
+        stream.ack_consumer("billing", lsn(50));
+        assert_eq!(*stream.last_ack_lsn.borrow(), lsn(10));
+
+        stream.detach_consumer("billing");
+        assert_eq!(*stream.last_ack_lsn.borrow(), lsn(50));
+        let saved = std::fs::read_to_string(dir.0.join(CONSUMERS_FILE)).unwrap();
+        assert_eq!(
+            serde_json::from_str::<HashMap<String, String>>(&saved).unwrap()["billing"],
+            lsn(50).to_string()
+        );
+    }
+
+    #[test]
+    fn slot_follows_the_slowest_session() {
+        let stream = stream(0, 0);
+        let fast = stream.register_session();
+        let slow = stream.register_session();
+
+        stream.ack_session(fast, lsn(100));
+        assert_eq!(*stream.last_ack_lsn.borrow(), lsn(0));
+        stream.ack_session(slow, lsn(50));
+        assert_eq!(*stream.last_ack_lsn.borrow(), lsn(50));
+        stream.ack_session(slow, lsn(150));
+        assert_eq!(*stream.last_ack_lsn.borrow(), lsn(100));
+
+        // A late acknowledgement of an older event doesn't move a session back
+        stream.ack_session(fast, lsn(20));
+        assert_eq!(*stream.last_ack_lsn.borrow(), lsn(100));
+
+        // A new session starts from the minimum, a disconnected one no longer holds it back
+        let new = stream.register_session();
+        stream.unregister_session(fast);
+        assert_eq!(*stream.last_ack_lsn.borrow(), lsn(100));
+        stream.ack_session(new, lsn(200));
+        assert_eq!(*stream.last_ack_lsn.borrow(), lsn(150));
+        stream.unregister_session(slow);
+        assert_eq!(*stream.last_ack_lsn.borrow(), lsn(200));
+    }
 }
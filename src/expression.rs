@@ -0,0 +1,810 @@
+use serde_json::{Number, Value};
+use std::cmp::Ordering;
+
+/// Levels of parentheses and NOT a filter may nest, deeper filters are rejected rather than
+/// risking a stack overflow while parsing or evaluating them
+const MAX_DEPTH: usize = 64;
+
+/// Row predicate of a subscription, e.g. `status = 'paid' AND amount > 100`.
+///
+/// Columns refer to the row of the event, which is the deleted row for deletes. `new.column`
+/// and `old.column` refer to the new and old row images explicitly. Comparisons follow SQL:
+/// anything compared to NULL is unknown, and a row is only sent if the predicate is true.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    /// Operands of a chain of ANDs, kept flat so long chains do not nest
+    And(Vec<Expression>),
+    Or(Vec<Expression>),
+    Not(Box<Expression>),
+    Compare(Operand, Comparison, Operand),
+    In {
+        operand: Operand,
+        list: Vec<Operand>,
+        negated: bool,
+    },
+    IsNull {
+        operand: Operand,
+        negated: bool,
+    },
+    /// Boolean column used as a condition on its own
+    Truthy(Operand),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Column(RowImage, String),
+    Literal(Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RowImage {
+    /// New row, or the old row for deletes
+    Row,
+    New,
+    Old,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+/// Row images of an event, decoded from its JSON payloads
+#[derive(Debug, Default)]
+pub struct Row<'a> {
+    pub new: Option<&'a Value>,
+    pub old: Option<&'a Value>,
+}
+
+impl Expression {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            input_len: input.chars().count(),
+            depth: 0,
+        };
+        let expression = parser.or()?;
+        match parser.peek() {
+            None => Ok(expression),
+            Some(token) => Err(parser.error_at(token, "expected AND, OR or end of filter")),
+        }
+    }
+
+    /// Whether the predicate is true for the row, unknown counts as false
+    pub fn matches(&self, row: &Row) -> bool {
+        self.eval(row) == Some(true)
+    }
+
+    /// Three-valued evaluation, `None` is unknown
+    fn eval(&self, row: &Row) -> Option<bool> {
+        match self {
+            Expression::And(expressions) => {
+                let mut result = Some(true);
+                for expression in expressions {
+                    match expression.eval(row) {
+                        Some(false) => return Some(false),
+                        Some(true) => {}
+                        None => result = None,
+                    }
+                }
+                result
+            }
+            Expression::Or(expressions) => {
+                let mut result = Some(false);
+                for expression in expressions {
+                    match expression.eval(row) {
+                        Some(true) => return Some(true),
+                        Some(false) => {}
+                        None => result = None,
+                    }
+                }
+                result
+            }
+            Expression::Not(expression) => expression.eval(row).map(|value| !value),
+            Expression::Compare(left, comparison, right) => {
+                let ordering = compare(&left.value(row), &right.value(row))?;
+                Some(match comparison {
+                    Comparison::Eq => ordering == Ordering::Equal,
+                    Comparison::NotEq => ordering != Ordering::Equal,
+                    Comparison::Lt => ordering == Ordering::Less,
+                    Comparison::LtEq => ordering != Ordering::Greater,
+                    Comparison::Gt => ordering == Ordering::Greater,
+                    Comparison::GtEq => ordering != Ordering::Less,
+                })
+            }
+            Expression::In {
+                operand,
+                list,
+                negated,
+            } => {
+                let value = operand.value(row);
+                let mut found = Some(false);
+                for item in list {
+                    match compare(&value, &item.value(row)) {
+                        Some(Ordering::Equal) => {
+                            found = Some(true);
+                            break;
+                        }
+                        Some(_) => {}
+                        None => found = None,
+                    }
+                }
+                found.map(|found| found != *negated)
+            }
+            Expression::IsNull { operand, negated } => {
+                Some(operand.value(row).is_null() != *negated)
+            }
+            Expression::Truthy(operand) => operand.value(row).as_bool(),
+        }
+    }
+}
+
+impl Operand {
+    fn value(&self, row: &Row) -> Value {
+        match self {
+            Operand::Literal(value) => value.clone(),
+            Operand::Column(image, column) => {
+                let image = match image {
+                    RowImage::Row => row.new.or(row.old),
+                    RowImage::New => row.new,
+                    RowImage::Old => row.old,
+                };
+                image
+                    .and_then(|image| image.get(column))
+                    .cloned()
+                    .unwrap_or(Value::Null)
+            }
+        }
+    }
+}
+
+/// Order two scalar values. Numbers compare with numeric strings, as `numeric` columns are
+/// sent as strings. Returns `None` for NULL and values of different types.
+fn compare(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Number(left), Value::Number(right)) => compare_numbers(left, right),
+        (Value::Number(number), Value::String(text)) => {
+            compare_numbers(number, &parse_number(text)?)
+        }
+        (Value::String(text), Value::Number(number)) => {
+            compare_numbers(&parse_number(text)?, number)
+        }
+        (Value::String(left), Value::String(right)) => Some(left.cmp(right)),
+        (Value::Bool(left), Value::Bool(right)) => Some(left.cmp(right)),
+        _ => None,
+    }
+}
+
+/// Integers compare exactly, e.g. `bigint` ids beyond 2^53, other numbers as floats
+fn compare_numbers(left: &Number, right: &Number) -> Option<Ordering> {
+    if let (Some(left), Some(right)) = (left.as_i64(), right.as_i64()) {
+        return Some(left.cmp(&right));
+    }
+    if let (Some(left), Some(right)) = (left.as_u64(), right.as_u64()) {
+        return Some(left.cmp(&right));
+    }
+    left.as_f64()?.partial_cmp(&right.as_f64()?)
+}
+
+/// Integer if the text is one, otherwise a finite float
+fn parse_number(text: &str) -> Option<Number> {
+    if let Ok(integer) = text.parse::<i64>() {
+        return Some(integer.into());
+    }
+    if let Ok(integer) = text.parse::<u64>() {
+        return Some(integer.into());
+    }
+    Number::from_f64(text.parse().ok()?)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Ident(String),
+    QuotedIdent(String),
+    String(String),
+    Number(Number),
+    Comparison(Comparison),
+    LParen,
+    RParen,
+    Comma,
+    Dot,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    /// Character offset in the filter, for error messages
+    position: usize,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let position = i;
+        let kind = match c {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => TokenKind::LParen,
+            ')' => TokenKind::RParen,
+            ',' => TokenKind::Comma,
+            '.' => TokenKind::Dot,
+            '=' => TokenKind::Comparison(Comparison::Eq),
+            '<' | '>' | '!' => {
+                let next = chars.get(i + 1).copied();
+                let (comparison, len) = match (c, next) {
+                    ('<', Some('=')) => (Comparison::LtEq, 2),
+                    ('<', Some('>')) => (Comparison::NotEq, 2),
+                    ('<', _) => (Comparison::Lt, 1),
+                    ('>', Some('=')) => (Comparison::GtEq, 2),
+                    ('>', _) => (Comparison::Gt, 1),
+                    ('!', Some('=')) => (Comparison::NotEq, 2),
+                    _ => return Err(format!("unexpected '!' at position {}", position + 1)),
+                };
+                i += len;
+                tokens.push(Token {
+                    kind: TokenKind::Comparison(comparison),
+                    position,
+                });
+                continue;
+            }
+            '\'' | '"' => {
+                // Quotes are escaped by doubling them, as in SQL
+                let mut text = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        Some(&q) if q == c && chars.get(i + 1) == Some(&c) => {
+                            text.push(c);
+                            i += 2;
+                        }
+                        Some(&q) if q == c => break,
+                        Some(&other) => {
+                            text.push(other);
+                            i += 1;
+                        }
+                        None => {
+                            return Err(format!(
+                                "unterminated {} at position {}",
+                                if c == '\'' {
+                                    "string"
+                                } else {
+                                    "quoted identifier"
+                                },
+                                position + 1
+                            ));
+                        }
+                    }
+                }
+                i += 1;
+                tokens.push(Token {
+                    kind: if c == '\'' {
+                        TokenKind::String(text)
+                    } else {
+                        TokenKind::QuotedIdent(text)
+                    },
+                    position,
+                });
+                continue;
+            }
+            c if c.is_ascii_digit()
+                || (c == '-' && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit())) =>
+            {
+                let start = i;
+                i += 1;
+                while chars
+                    .get(i)
+                    .is_some_and(|&c| c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E')
+                {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                // Integers stay integers, so they compare exactly
+                let number = parse_number(&text).ok_or_else(|| {
+                    format!("invalid number '{}' at position {}", text, start + 1)
+                })?;
+                tokens.push(Token {
+                    kind: TokenKind::Number(number),
+                    position,
+                });
+                continue;
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let start = i;
+                while chars
+                    .get(i)
+                    .is_some_and(|&c| c.is_alphanumeric() || c == '_' || c == '$')
+                {
+                    i += 1;
+                }
+                tokens.push(Token {
+                    kind: TokenKind::Ident(chars[start..i].iter().collect()),
+                    position,
+                });
+                continue;
+            }
+            other => {
+                return Err(format!(
+                    "unexpected '{}' at position {}",
+                    other,
+                    position + 1
+                ));
+            }
+        };
+        tokens.push(Token { kind, position });
+        i += 1;
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    input_len: usize,
+    /// Levels of parentheses and NOT around the current position
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    /// Consume the next token if it is the keyword, case-insensitively
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let matched = matches!(
+            self.peek(),
+            Some(Token { kind: TokenKind::Ident(ident), .. }) if ident.eq_ignore_ascii_case(keyword)
+        );
+        if matched {
+            self.position += 1;
+        }
+        matched
+    }
+
+    fn expect(&mut self, kind: TokenKind, expected: &str) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token.kind == kind => Ok(()),
+            Some(token) => Err(self.error_at(&token, &format!("expected {}", expected))),
+            None => Err(self.error_at_end(&format!("expected {}", expected))),
+        }
+    }
+
+    fn error_at(&self, token: &Token, message: &str) -> String {
+        format!("{} at position {}", message, token.position + 1)
+    }
+
+    fn error_at_end(&self, message: &str) -> String {
+        format!("{} at position {}", message, self.input_len + 1)
+    }
+
+    /// Enter a NOT or parenthesized expression starting at `token`
+    fn nest(&mut self, token: &Token) -> Result<(), String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error_at(
+                token,
+                &format!("filter nested deeper than {} levels", MAX_DEPTH),
+            ));
+        }
+        Ok(())
+    }
+
+    fn or(&mut self) -> Result<Expression, String> {
+        let mut expressions = vec![self.and()?];
+        while self.keyword("OR") {
+            expressions.push(self.and()?);
+        }
+        Ok(match expressions.len() {
+            1 => expressions.pop().unwrap(),
+            _ => Expression::Or(expressions),
+        })
+    }
+
+    fn and(&mut self) -> Result<Expression, String> {
+        let mut expressions = vec![self.not()?];
+        while self.keyword("AND") {
+            expressions.push(self.not()?);
+        }
+        Ok(match expressions.len() {
+            1 => expressions.pop().unwrap(),
+            _ => Expression::And(expressions),
+        })
+    }
+
+    fn not(&mut self) -> Result<Expression, String> {
+        if let Some(token) = self.peek().cloned()
+            && self.keyword("NOT")
+        {
+            self.nest(&token)?;
+            let expression = Expression::Not(Box::new(self.not()?));
+            self.depth -= 1;
+            return Ok(expression);
+        }
+        self.predicate()
+    }
+
+    fn predicate(&mut self) -> Result<Expression, String> {
+        if let Some(token) = self.peek().cloned()
+            && token.kind == TokenKind::LParen
+        {
+            self.position += 1;
+            self.nest(&token)?;
+            let expression = self.or()?;
+            self.expect(TokenKind::RParen, "')'")?;
+            self.depth -= 1;
+            return Ok(expression);
+        }
+
+        let operand = self.operand()?;
+
+        if self.keyword("IS") {
+            let negated = self.keyword("NOT");
+            if !self.keyword("NULL") {
+                return Err(match self.peek() {
+                    Some(token) => self.error_at(token, "expected NULL"),
+                    None => self.error_at_end("expected NULL"),
+                });
+            }
+            return Ok(Expression::IsNull { operand, negated });
+        }
+
+        let negated = self.keyword("NOT");
+        if self.keyword("IN") {
+            self.expect(TokenKind::LParen, "'(' after IN")?;
+            let mut list = vec![self.operand()?];
+            loop {
+                match self.next() {
+                    Some(Token {
+                        kind: TokenKind::Comma,
+                        ..
+                    }) => list.push(self.operand()?),
+                    Some(Token {
+                        kind: TokenKind::RParen,
+                        ..
+                    }) => break,
+                    Some(token) => return Err(self.error_at(&token, "expected ',' or ')'")),
+                    None => return Err(self.error_at_end("expected ',' or ')'")),
+                }
+            }
+            return Ok(Expression::In {
+                operand,
+                list,
+                negated,
+            });
+        }
+        if negated {
+            return Err(match self.peek() {
+                Some(token) => self.error_at(token, "expected IN after NOT"),
+                None => self.error_at_end("expected IN after NOT"),
+            });
+        }
+
+        if let Some(Token {
+            kind: TokenKind::Comparison(comparison),
+            ..
+        }) = self.peek()
+        {
+            let comparison = *comparison;
+            self.position += 1;
+            return Ok(Expression::Compare(operand, comparison, self.operand()?));
+        }
+
+        match operand {
+            Operand::Column(..) => Ok(Expression::Truthy(operand)),
+            Operand::Literal(_) => Err(match self.peek() {
+                Some(token) => self.error_at(token, "expected comparison"),
+                None => self.error_at_end("expected comparison"),
+            }),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        let Some(token) = self.next() else {
+            return Err(self.error_at_end("expected column or value"));
+        };
+        match token.kind {
+            TokenKind::String(text) => Ok(Operand::Literal(Value::String(text))),
+            TokenKind::Number(number) => Ok(Operand::Literal(Value::Number(number))),
+            TokenKind::Ident(ident) if ident.eq_ignore_ascii_case("NULL") => {
+                Ok(Operand::Literal(Value::Null))
+            }
+            TokenKind::Ident(ident) if ident.eq_ignore_ascii_case("TRUE") => {
+                Ok(Operand::Literal(Value::Bool(true)))
+            }
+            TokenKind::Ident(ident) if ident.eq_ignore_ascii_case("FALSE") => {
+                Ok(Operand::Literal(Value::Bool(false)))
+            }
+            TokenKind::Ident(ref ident) if is_reserved(ident) => {
+                Err(self.error_at(&token, &format!("unexpected {}", ident.to_uppercase())))
+            }
+            TokenKind::QuotedIdent(column) => Ok(Operand::Column(RowImage::Row, column)),
+            TokenKind::Ident(ident) => {
+                let image = match ident.as_str() {
+                    "new" => RowImage::New,
+                    "old" => RowImage::Old,
+                    _ => return Ok(Operand::Column(RowImage::Row, ident)),
+                };
+                if !matches!(
+                    self.peek(),
+                    Some(Token {
+                        kind: TokenKind::Dot,
+                        ..
+                    })
+                ) {
+                    return Ok(Operand::Column(RowImage::Row, ident));
+                }
+                self.position += 1;
+                match self.next() {
+                    Some(Token {
+                        kind: TokenKind::Ident(column) | TokenKind::QuotedIdent(column),
+                        ..
+                    }) => Ok(Operand::Column(image, column)),
+                    Some(token) => Err(self.error_at(&token, "expected column name")),
+                    None => Err(self.error_at_end("expected column name")),
+                }
+            }
+            _ => Err(self.error_at(&token, "expected column or value")),
+        }
+    }
+}
+
+fn is_reserved(ident: &str) -> bool {
+    ["AND", "OR", "NOT", "IN", "IS"]
+        .iter()
+        .any(|keyword| ident.eq_ignore_ascii_case(keyword))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn column(name: &str) -> Operand {
+        Operand::Column(RowImage::Row, name.to_string())
+    }
+
+    fn literal(value: Value) -> Operand {
+        Operand::Literal(value)
+    }
+
+    /// Evaluate `filter` against an insert of `new`
+    fn eval(filter: &str, new: Value) -> Option<bool> {
+        Expression::parse(filter).unwrap().eval(&Row {
+            new: Some(&new),
+            old: None,
+        })
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let a = Expression::Truthy(column("a"));
+        let b = Expression::Truthy(column("b"));
+        let c = Expression::Truthy(column("c"));
+        assert_eq!(
+            Expression::parse("a OR b AND c").unwrap(),
+            Expression::Or(vec![a.clone(), Expression::And(vec![b.clone(), c.clone()])])
+        );
+        assert_eq!(
+            Expression::parse("(a OR b) AND c").unwrap(),
+            Expression::And(vec![Expression::Or(vec![a.clone(), b.clone()]), c.clone()])
+        );
+        assert_eq!(
+            Expression::parse("NOT a AND b").unwrap(),
+            Expression::And(vec![Expression::Not(Box::new(a)), b])
+        );
+        assert_eq!(
+            eval("a OR b AND c", json!({"a": true, "b": false})),
+            Some(true)
+        );
+        assert_eq!(
+            eval("(a OR b) AND c", json!({"a": true, "c": false})),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn parses_operands_and_keywords() {
+        assert_eq!(
+            Expression::parse(r#"new."Total" >= -1.5 and old.status <> 'it''s'"#).unwrap(),
+            Expression::And(vec![
+                Expression::Compare(
+                    Operand::Column(RowImage::New, "Total".to_string()),
+                    Comparison::GtEq,
+                    literal(json!(-1.5))
+                ),
+                Expression::Compare(
+                    Operand::Column(RowImage::Old, "status".to_string()),
+                    Comparison::NotEq,
+                    literal(json!("it's"))
+                ),
+            ])
+        );
+        assert_eq!(
+            Expression::parse("deleted_at is not null").unwrap(),
+            Expression::IsNull {
+                operand: column("deleted_at"),
+                negated: true
+            }
+        );
+        assert_eq!(
+            Expression::parse("id NOT IN (1, NULL)").unwrap(),
+            Expression::In {
+                operand: column("id"),
+                list: vec![literal(json!(1)), literal(Value::Null)],
+                negated: true
+            }
+        );
+        // `new` and `old` without a dot are plain columns
+        assert_eq!(
+            Expression::parse("new").unwrap(),
+            Expression::Truthy(column("new"))
+        );
+    }
+
+    #[test]
+    fn reports_parse_errors_with_position() {
+        for (filter, error) in [
+            ("", "expected column or value at position 1"),
+            ("a =", "expected column or value at position 4"),
+            ("a = 'x", "unterminated string at position 5"),
+            ("(a", "expected ')' at position 3"),
+            ("a b", "expected AND, OR or end of filter at position 3"),
+            ("a NOT = 1", "expected IN after NOT at position 7"),
+            ("a IS 1", "expected NULL at position 6"),
+            ("a IN (1 2)", "expected ',' or ')' at position 9"),
+            ("1", "expected comparison at position 2"),
+            ("a = AND", "unexpected AND at position 5"),
+            ("a ! 1", "unexpected '!' at position 3"),
+            ("a = 1.2.3", "invalid number '1.2.3' at position 5"),
+            ("new. = 1", "expected column name at position 6"),
+        ] {
+            assert_eq!(
+                Expression::parse(filter),
+                Err(error.to_string()),
+                "{}",
+                filter
+            );
+        }
+    }
+
+    #[test]
+    fn limits_nesting_depth() {
+        let nested = |depth: usize| format!("{}a = 1{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(Expression::parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            Expression::parse(&nested(MAX_DEPTH + 1)),
+            Err("filter nested deeper than 64 levels at position 65".to_string())
+        );
+        assert!(Expression::parse(&format!("{}a", "NOT ".repeat(MAX_DEPTH))).is_ok());
+        assert!(Expression::parse(&format!("{}a", "NOT ".repeat(MAX_DEPTH + 1))).is_err());
+        assert!(Expression::parse(&format!("{}a", "NOT (".repeat(40))).is_err());
+
+        // Chains of AND and OR do not nest
+        let chain = vec!["a = 1"; 10_000].join(" OR ");
+        assert_eq!(eval(&chain, json!({"a": 1})), Some(true));
+        assert_eq!(eval(&chain, json!({"a": 2})), Some(false));
+    }
+
+    #[test]
+    fn comparisons_with_null_are_unknown() {
+        let row = json!({"a": null, "b": 1});
+        assert_eq!(eval("a = 1", row.clone()), None);
+        assert_eq!(eval("a <> 1", row.clone()), None);
+        assert_eq!(eval("b = NULL", row.clone()), None);
+        assert_eq!(eval("missing = 1", row.clone()), None);
+        assert_eq!(eval("NOT a = 1", row.clone()), None);
+        assert_eq!(eval("a IS NULL", row.clone()), Some(true));
+        assert_eq!(eval("b IS NOT NULL", row.clone()), Some(true));
+        assert_eq!(eval("missing IS NULL", row.clone()), Some(true));
+        assert_eq!(eval("a", row.clone()), None);
+
+        // Unknown only decides AND and OR if the other side does not
+        assert_eq!(eval("a = 1 AND b = 1", row.clone()), None);
+        assert_eq!(eval("a = 1 AND b = 2", row.clone()), Some(false));
+        assert_eq!(eval("a = 1 OR b = 1", row.clone()), Some(true));
+        assert_eq!(eval("a = 1 OR b = 2", row.clone()), None);
+
+        let filter = Expression::parse("a = 1").unwrap();
+        assert!(!filter.matches(&Row {
+            new: Some(&row),
+            old: None
+        }));
+        let filter = Expression::parse("NOT a = 1").unwrap();
+        assert!(!filter.matches(&Row {
+            new: Some(&row),
+            old: None
+        }));
+    }
+
+    #[test]
+    fn in_lists_follow_sql() {
+        let row = json!({"a": 1, "n": null});
+        assert_eq!(eval("a IN (1, 2)", row.clone()), Some(true));
+        assert_eq!(eval("a IN (2, 3)", row.clone()), Some(false));
+        assert_eq!(eval("a IN (1, NULL)", row.clone()), Some(true));
+        assert_eq!(eval("a IN (2, NULL)", row.clone()), None);
+        assert_eq!(eval("a NOT IN (2, 3)", row.clone()), Some(true));
+        assert_eq!(eval("a NOT IN (1, 2)", row.clone()), Some(false));
+        assert_eq!(eval("a NOT IN (2, NULL)", row.clone()), None);
+        assert_eq!(eval("a NOT IN (1, NULL)", row.clone()), Some(false));
+        assert_eq!(eval("n IN (1, 2)", row.clone()), None);
+        assert_eq!(eval("n NOT IN (1, 2)", row.clone()), None);
+        assert_eq!(eval("a IN ('1', b)", row), Some(true));
+    }
+
+    #[test]
+    fn compares_values() {
+        let row = json!({
+            "id": 9007199254740993_i64,
+            "big": u64::MAX,
+            "price": "12.50",
+            "ratio": 0.5,
+            "name": "b",
+            "flag": true
+        });
+        // Integers beyond 2^53 are not rounded
+        assert_eq!(eval("id = 9007199254740993", row.clone()), Some(true));
+        assert_eq!(eval("id = 9007199254740992", row.clone()), Some(false));
+        assert_eq!(eval("id > 9007199254740992", row.clone()), Some(true));
+        assert_eq!(eval("big = 18446744073709551615", row.clone()), Some(true));
+        assert_eq!(eval("big > -1", row.clone()), Some(true));
+        // numeric columns are sent as strings
+        assert_eq!(eval("price = 12.5", row.clone()), Some(true));
+        assert_eq!(eval("price > 12", row.clone()), Some(true));
+        assert_eq!(eval("ratio < 1", row.clone()), Some(true));
+        assert_eq!(eval("name >= 'a'", row.clone()), Some(true));
+        assert_eq!(eval("name != 'b'", row.clone()), Some(false));
+        assert_eq!(eval("flag = true", row.clone()), Some(true));
+        assert_eq!(eval("flag", row.clone()), Some(true));
+        // Values of different types are not comparable
+        assert_eq!(eval("name = 1", row.clone()), None);
+        assert_eq!(eval("flag = 'true'", row), None);
+    }
+
+    #[test]
+    fn row_images() {
+        let new = json!({"status": "paid"});
+        let old = json!({"status": "open"});
+        let update = Row {
+            new: Some(&new),
+            old: Some(&old),
+        };
+        let delete = Row {
+            new: None,
+            old: Some(&old),
+        };
+        let matches = |filter: &str, row: &Row| Expression::parse(filter).unwrap().matches(row);
+
+        assert!(matches("status = 'paid'", &update));
+        assert!(matches(
+            "new.status = 'paid' AND old.status = 'open'",
+            &update
+        ));
+        assert!(!matches("old.status = 'paid'", &update));
+        // Columns without an image refer to the deleted row for deletes
+        assert!(matches("status = 'open'", &delete));
+        assert!(matches("old.status = 'open'", &delete));
+        assert!(!matches("new.status = 'open'", &delete));
+        assert!(matches("new.status IS NULL", &delete));
+    }
+}
@@ -0,0 +1,385 @@
+use crate::expression::{Expression, Row};
+use crate::grpc::plugin::{ClientSubscribe, Operation, server_message::Msg};
+use serde_json::Value;
+use std::collections::HashSet;
+
+/// Sources, tables, operations and columns a session subscribed to. Transaction boundaries and
+/// other events without a table are always sent for the subscribed sources, so clients can keep
+/// acknowledging them.
+#[derive(Debug, Clone, Default)]
+pub struct SubscriptionFilter {
+    sources: HashSet<String>,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    operations: HashSet<Operation>,
+    columns: HashSet<String>,
+    /// Row predicate, evaluated before the columns are projected
+    predicate: Option<Expression>,
+}
+
+impl SubscriptionFilter {
+    pub fn new(subscribe: ClientSubscribe) -> Result<Self, String> {
+        let operations = subscribe
+            .operations
+            .iter()
+            .map(|&operation| {
+                Operation::try_from(operation)
+                    .map_err(|_| format!("Unknown operation {}", operation))
+            })
+            .collect::<Result<HashSet<_>, _>>()?;
+
+        for pattern in subscribe.include.iter().chain(&subscribe.exclude) {
+            if pattern.is_empty() {
+                return Err("Table patterns must not be empty".to_string());
+            }
+        }
+        if subscribe.columns.iter().any(|column| column.is_empty()) {
+            return Err("Column names must not be empty".to_string());
+        }
+        let predicate = match subscribe.filter.trim() {
+            "" => None,
+            filter => {
+                Some(Expression::parse(filter).map_err(|e| format!("Invalid filter: {}", e))?)
+            }
+        };
+
+        Ok(Self {
+            sources: subscribe.sources.into_iter().collect(),
+            include: subscribe.include,
+            exclude: subscribe.exclude,
+            operations,
+            columns: subscribe.columns.into_iter().collect(),
+            predicate,
+        })
+    }
+
+    /// The event as the session should see it, or `None` if it is filtered out
+    pub fn apply(&self, msg: Msg) -> Option<Msg> {
+        match msg {
+            Msg::Insert(mut ev) => {
+                if !self.accepts(&ev.schema, &ev.table, Operation::Insert)
+                    || !self.row_matches(&ev.json_payload, "")
+                {
+                    return None;
+                }
+                ev.json_payload = self.project(&ev.json_payload);
+                Some(Msg::Insert(ev))
+            }
+            Msg::Update(mut ev) => {
+                if !self.accepts(&ev.schema, &ev.table, Operation::Update)
+                    || !self.row_matches(&ev.json_payload, &ev.old_json_payload)
+                {
+                    return None;
+                }
+                ev.json_payload = self.project(&ev.json_payload);
+                ev.old_json_payload = self.project(&ev.old_json_payload);
+                ev.key_json_payload = self.project(&ev.key_json_payload);
+                ev.unchanged_columns
+                    .retain(|column| self.includes_column(column));
+                Some(Msg::Update(ev))
+            }
+            Msg::Delete(mut ev) => {
+                if !self.accepts(&ev.schema, &ev.table, Operation::Delete)
+                    || !self.row_matches("", &ev.json_payload)
+                {
+                    return None;
+                }
+                ev.json_payload = self.project(&ev.json_payload);
+                ev.old_json_payload = self.project(&ev.old_json_payload);
+                ev.key_json_payload = self.project(&ev.key_json_payload);
+                Some(Msg::Delete(ev))
+            }
+            Msg::Truncate(ev) => self
+                .accepts(&ev.schema, &ev.table, Operation::Truncate)
+                .then_some(Msg::Truncate(ev)),
+            Msg::Schema(mut ev) => {
+                if !self.includes_table(&ev.schema, &ev.table) {
+                    return None;
+                }
+                ev.columns
+                    .retain(|column| self.includes_column(&column.name));
+                Some(Msg::Schema(ev))
+            }
+            // Snapshot rows are sent like inserts of the existing rows
+            Msg::Snapshot(mut ev) => {
+                if !self.accepts(&ev.schema, &ev.table, Operation::Insert)
+                    || !self.row_matches(&ev.json_payload, "")
+                {
+                    return None;
+                }
+                ev.json_payload = self.project(&ev.json_payload);
+                Some(Msg::Snapshot(ev))
+            }
+            Msg::SnapshotProgress(ev) => (ev.table.is_empty()
+                || self.includes_table(&ev.schema, &ev.table))
+            .then_some(Msg::SnapshotProgress(ev)),
+            msg => Some(msg),
+        }
+    }
+
+    pub fn includes_source(&self, source: &str) -> bool {
+        self.sources.is_empty() || self.sources.contains(source)
+    }
+
+    fn accepts(&self, schema: &str, table: &str, operation: Operation) -> bool {
+        (self.operations.is_empty() || self.operations.contains(&operation))
+            && self.includes_table(schema, table)
+    }
+
+    fn includes_table(&self, schema: &str, table: &str) -> bool {
+        let matches = |pattern: &String| table_matches(pattern, schema, table);
+        (self.include.is_empty() || self.include.iter().any(matches))
+            && !self.exclude.iter().any(matches)
+    }
+
+    fn includes_column(&self, column: &str) -> bool {
+        self.columns.is_empty() || self.columns.contains(column)
+    }
+
+    /// Evaluate the row predicate on the new and old JSON rows, empty if the event has none
+    fn row_matches(&self, new: &str, old: &str) -> bool {
+        let Some(predicate) = &self.predicate else {
+            return true;
+        };
+        // An empty payload does not parse, so a missing row image is `None`
+        let new = serde_json::from_str::<Value>(new).ok();
+        let old = serde_json::from_str::<Value>(old).ok();
+        predicate.matches(&Row {
+            new: new.as_ref(),
+            old: old.as_ref(),
+        })
+    }
+
+    /// Keep only the projected columns of a JSON row
+    fn project(&self, json_payload: &str) -> String {
+        if self.columns.is_empty() || json_payload.is_empty() {
+            return json_payload.to_string();
+        }
+        match serde_json::from_str::<Value>(json_payload) {
+            Ok(Value::Object(mut row)) => {
+                row.retain(|column, _| self.columns.contains(column));
+                Value::Object(row).to_string()
+            }
+            _ => json_payload.to_string(),
+        }
+    }
+}
+
+/// Match a `schema.table` glob, or a table name glob in any schema if it has no dot
+fn table_matches(pattern: &str, schema: &str, table: &str) -> bool {
+    match pattern.split_once('.') {
+        Some((schema_pattern, table_pattern)) => {
+            glob_matches(schema_pattern, schema) && glob_matches(table_pattern, table)
+        }
+        None => glob_matches(pattern, table),
+    }
+}
+
+/// Glob with `*` for any run of characters and `?` for a single character
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position after the last `*` and the text it matched up to, for backtracking
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::plugin::{CommitEvent, DeleteEvent, InsertEvent, TruncateEvent, UpdateEvent};
+    use serde_json::json;
+
+    fn filter(subscribe: ClientSubscribe) -> SubscriptionFilter {
+        SubscriptionFilter::new(subscribe).unwrap()
+    }
+
+    fn insert(table: &str, row: Value) -> Msg {
+        let (schema, table) = table.split_once('.').unwrap();
+        Msg::Insert(InsertEvent {
+            schema: schema.to_string(),
+            table: table.to_string(),
+            json_payload: row.to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_matches("orders", "orders"));
+        assert!(!glob_matches("orders", "orders_2024"));
+        assert!(glob_matches("orders*", "orders_2024"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("*_2024", "orders_2024"));
+        assert!(glob_matches("o*s_*4", "orders_2024"));
+        assert!(!glob_matches("o*s_*5", "orders_2024"));
+        assert!(glob_matches("order?", "orders"));
+        assert!(!glob_matches("order?", "order"));
+        assert!(glob_matches("*a*a*", "banana"));
+        assert!(!glob_matches("*a*b", "banana"));
+        assert!(glob_matches("ü?*", "über"));
+        assert!(!glob_matches("", "orders"));
+        assert!(glob_matches("", ""));
+    }
+
+    #[test]
+    fn table_patterns() {
+        assert!(table_matches("public.orders", "public", "orders"));
+        assert!(!table_matches("public.orders", "sales", "orders"));
+        assert!(table_matches("orders", "sales", "orders"));
+        assert!(table_matches("*.order_*", "sales", "order_items"));
+        assert!(table_matches("s*.*", "sales", "orders"));
+        assert!(!table_matches("s*.*", "public", "orders"));
+    }
+
+    #[test]
+    fn includes_and_excludes_tables() {
+        let filter = filter(ClientSubscribe {
+            include: vec!["public.*".to_string()],
+            exclude: vec!["*_audit".to_string()],
+            ..Default::default()
+        });
+        assert!(filter.apply(insert("public.orders", json!({}))).is_some());
+        assert!(
+            filter
+                .apply(insert("public.orders_audit", json!({})))
+                .is_none()
+        );
+        assert!(filter.apply(insert("sales.orders", json!({}))).is_none());
+        // Events without a table are always sent
+        assert!(filter.apply(Msg::Commit(CommitEvent::default())).is_some());
+    }
+
+    #[test]
+    fn selects_operations() {
+        let filter = filter(ClientSubscribe {
+            operations: vec![Operation::Delete as i32, Operation::Truncate as i32],
+            ..Default::default()
+        });
+        assert!(filter.apply(insert("public.orders", json!({}))).is_none());
+        assert!(filter.apply(Msg::Delete(DeleteEvent::default())).is_some());
+        assert!(
+            filter
+                .apply(Msg::Truncate(TruncateEvent::default()))
+                .is_some()
+        );
+
+        assert!(
+            SubscriptionFilter::new(ClientSubscribe {
+                operations: vec![42],
+                ..Default::default()
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn projects_every_row_payload() {
+        let filter = filter(ClientSubscribe {
+            columns: vec!["id".to_string(), "status".to_string()],
+            ..Default::default()
+        });
+        let Some(Msg::Update(ev)) = filter.apply(Msg::Update(UpdateEvent {
+            json_payload: json!({"id": 1, "status": "paid", "card": "4111"}).to_string(),
+            old_json_payload: json!({"id": 1, "status": "open", "card": "4111"}).to_string(),
+            key_json_payload: json!({"id": 1, "card": "4111"}).to_string(),
+            unchanged_columns: vec!["notes".to_string(), "status".to_string()],
+            ..Default::default()
+        })) else {
+            panic!("update was filtered out");
+        };
+        assert_eq!(
+            ev.json_payload,
+            json!({"id": 1, "status": "paid"}).to_string()
+        );
+        assert_eq!(
+            ev.old_json_payload,
+            json!({"id": 1, "status": "open"}).to_string()
+        );
+        assert_eq!(ev.key_json_payload, json!({"id": 1}).to_string());
+        assert_eq!(ev.unchanged_columns, vec!["status".to_string()]);
+
+        let Some(Msg::Delete(ev)) = filter.apply(Msg::Delete(DeleteEvent {
+            json_payload: json!({"id": 2, "card": "4111"}).to_string(),
+            key_json_payload: json!({"id": 2, "card": "4111"}).to_string(),
+            ..Default::default()
+        })) else {
+            panic!("delete was filtered out");
+        };
+        assert_eq!(ev.json_payload, json!({"id": 2}).to_string());
+        assert_eq!(ev.key_json_payload, json!({"id": 2}).to_string());
+        assert_eq!(ev.old_json_payload, "");
+    }
+
+    #[test]
+    fn evaluates_the_predicate_before_projecting() {
+        let filter = filter(ClientSubscribe {
+            columns: vec!["id".to_string()],
+            filter: "status = 'paid'".to_string(),
+            ..Default::default()
+        });
+        let Some(Msg::Insert(ev)) =
+            filter.apply(insert("public.orders", json!({"id": 1, "status": "paid"})))
+        else {
+            panic!("insert was filtered out");
+        };
+        assert_eq!(ev.json_payload, json!({"id": 1}).to_string());
+        assert!(
+            filter
+                .apply(insert("public.orders", json!({"id": 2, "status": "open"})))
+                .is_none()
+        );
+
+        // Deletes are matched against the deleted row
+        let delete = |status: &str| {
+            Msg::Delete(DeleteEvent {
+                json_payload: json!({"id": 3, "status": status}).to_string(),
+                ..Default::default()
+            })
+        };
+        assert!(filter.apply(delete("paid")).is_some());
+        assert!(filter.apply(delete("open")).is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_subscriptions() {
+        for subscribe in [
+            ClientSubscribe {
+                include: vec![String::new()],
+                ..Default::default()
+            },
+            ClientSubscribe {
+                columns: vec![String::new()],
+                ..Default::default()
+            },
+            ClientSubscribe {
+                filter: "status =".to_string(),
+                ..Default::default()
+            },
+        ] {
+            assert!(SubscriptionFilter::new(subscribe).is_err());
+        }
+    }
+}
@@ -1,20 +1,54 @@
-use crate::event_stream::EventStream;
+use crate::event_log::LogReader;
+use crate::event_stream::{EventStream, RequestedSnapshot};
+use crate::filter::SubscriptionFilter;
+use crate::snapshot::{self, PublishedTable, SnapshotTable, SqlFilter};
+use crate::transaction_buffer::{Drain, TransactionBuffer};
 use plugin::{
-    ClientAck, ClientMessage, ServerMessage,
+    ClientAck, ClientHello, ClientMessage, ClientRequest, ClientSubscribe, CommitEvent, Delivery,
+    RowChange, ServerMessage, SessionStartEvent, SnapshotRequest, SnapshotRequestAccepted,
+    TransactionEvent,
     plugin_service_server::{PluginService, PluginServiceServer},
+    row_change::Change,
+    server_message::Msg,
 };
+use prost::Message;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{Notify, mpsc, watch};
 use tokio_postgres::types::PgLsn;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status, transport::Server};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 pub mod plugin {
     tonic::include_proto!("plugin");
 }
 
+/// Size of the changes in a `TransactionEvent`, larger transactions are split into chunks so
+/// they stay below the 4 MiB message limit clients decode by default
+const MAX_TRANSACTION_EVENT_BYTES: usize = 1024 * 1024;
+
 #[derive(Debug)]
 pub struct PluginServer {
-    event_stream: EventStream,
+    /// Event stream of every source, a session streams all of them
+    event_streams: Vec<EventStream>,
+    transaction_memory_limit: usize,
+}
+
+impl PluginServer {
+    /// Event stream of the source named in a request, which may leave it out if there is only one
+    fn event_stream(&self, source: &str) -> Result<&EventStream, Status> {
+        match (source, self.event_streams.as_slice()) {
+            ("", [event_stream]) => Ok(event_stream),
+            ("", _) => Err(Status::invalid_argument(
+                "source is required with several sources",
+            )),
+            (source, event_streams) => event_streams
+                .iter()
+                .find(|event_stream| event_stream.source == source)
+                .ok_or_else(|| Status::invalid_argument(format!("Unknown source '{}'", source))),
+        }
+    }
 }
 
 #[tonic::async_trait]
@@ -30,70 +64,746 @@ impl PluginService for PluginServer {
         info!("Client connected: {}", addr);
 
         // Signal when client disconnects
-        let (shutdown_tx, mut shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
 
+        // Room for the first SessionStartEvent of every source
         let (client_tx, client_rx) =
-            tokio::sync::mpsc::channel::<Result<ServerMessage, Status>>(32);
+            mpsc::channel::<Result<ServerMessage, Status>>(32 + self.event_streams.len());
+
+        // Delivery mode requested by the client
+        let (delivery_tx, delivery_rx) = watch::channel(Delivery::Event);
+
+        // Events the client is ready for, unlimited until it requests a number of them
+        let (credits_tx, _) = watch::channel(None::<u64>);
+
+        // Sources, tables, operations and columns the client subscribed to
+        let (filter_tx, filter_rx) = watch::channel(None::<SubscriptionFilter>);
+
+        // Every source is forwarded by a task of its own, sharing the settings of the session
+        let mut sources = Vec::with_capacity(self.event_streams.len());
+        for event_stream in &self.event_streams {
+            // Acknowledged LSN of the session, replayed transactions before it are skipped
+            let (cursor_tx, cursor_rx) = watch::channel(*event_stream.last_ack_lsn.borrow());
+
+            // Position a client resumes from, the event log is read again from it
+            let (seek_tx, seek_rx) = watch::channel(None::<PgLsn>);
+
+            let events = match event_stream.log() {
+                Some(log) => EventSource::Log(log.reader()),
+                None => EventSource::Broadcast {
+                    rx: event_stream.tx.subscribe(),
+                    received: event_stream.received.clone(),
+                },
+            };
+
+            // Tell the client where the stream resumes, changes after it may be sent again
+            // if they were not acknowledged before a restart
+            let resume_lsn = u64::from(*event_stream.resume_lsn.borrow());
+            let _ = client_tx.try_send(Ok(ServerMessage {
+                msg: Some(Msg::SessionStart(SessionStartEvent {
+                    resume_lsn,
+                    consumer_id: String::new(),
+                })),
+                source: event_stream.source.clone(),
+            }));
+
+            tokio::spawn(
+                Forwarder {
+                    source: event_stream.source.clone(),
+                    events,
+                    cursor_rx,
+                    seek_rx,
+                    delivery_rx: delivery_rx.clone(),
+                    filter_rx: filter_rx.clone(),
+                    credits: credits_tx.clone(),
+                    client_tx: client_tx.clone(),
+                    shutdown_rx: shutdown_rx.clone(),
+                    transaction_memory_limit: self.transaction_memory_limit,
+                }
+                .run(),
+            );
+
+            sources.push(SessionSource {
+                session_id: event_stream.register_session(),
+                event_stream: event_stream.clone(),
+                cursor_tx,
+                seek_tx,
+                consumer_id: None,
+                resumed: false,
+                subscribed: true,
+            });
+        }
 
         // Spawn task to read client messages
         let mut stream = request.into_inner();
-        let tx_last_ack_lsn = self.event_stream.last_ack_lsn.clone();
+        let reader_tx = client_tx;
         tokio::spawn(async move {
+            // Consumer named in the client's hello
+            let mut consumer_id = None;
             while let Ok(Some(client_msg)) = stream.message().await {
-                match client_msg.msg {
-                    Some(plugin::client_message::Msg::Ack(ClientAck { pg_lsn })) => {
-                        tx_last_ack_lsn.send(PgLsn::from(pg_lsn)).ok();
+                let handled = match client_msg.msg {
+                    Some(plugin::client_message::Msg::Ack(ClientAck { pg_lsn, source })) => {
+                        find_source(&mut sources, &source)
+                            .map(|source| source.ack(PgLsn::from(pg_lsn)))
+                    }
+                    Some(plugin::client_message::Msg::Delivery(delivery)) => {
+                        debug!(
+                            "Client {} requested {:?} delivery",
+                            addr,
+                            delivery.delivery()
+                        );
+                        delivery_tx.send(delivery.delivery()).ok();
+                        Ok(())
+                    }
+                    Some(plugin::client_message::Msg::Subscribe(subscribe)) => {
+                        subscribe_sources(
+                            &mut sources,
+                            subscribe,
+                            consumer_id.as_deref(),
+                            &filter_tx,
+                            &reader_tx,
+                            &addr,
+                        )
+                        .await
+                    }
+                    Some(plugin::client_message::Msg::Request(ClientRequest { credits })) => {
+                        credits_tx.send_modify(|available| {
+                            *available = Some(available.unwrap_or(0).saturating_add(credits));
+                        });
+                        Ok(())
+                    }
+                    Some(plugin::client_message::Msg::Hello(hello)) => {
+                        resume(&mut sources, &mut consumer_id, hello, &reader_tx, &addr).await
                     }
-                    _ => {}
+                    None => Ok(()),
+                };
+                if let Err(status) = handled {
+                    let _ = reader_tx.send(Err(status)).await;
+                    break;
                 }
             }
 
             info!("Client disconnected: {}", addr);
+            for source in &sources {
+                source.close();
+            }
             let _ = shutdown_tx.send(());
         });
 
-        let mut broadcast_rx = self.event_stream.tx.subscribe();
-        tokio::spawn(async move {
-            loop {
-                tokio::select! {
-                    biased;
-                    _ = &mut shutdown_rx => {
-                        debug!("Stopping event forwarder task (client disconnected)");
-                        break;
+        Ok(Response::new(ReceiverStream::new(client_rx)))
+    }
+
+    async fn request_snapshot(
+        &self,
+        request: Request<SnapshotRequest>,
+    ) -> Result<Response<SnapshotRequestAccepted>, Status> {
+        let request = request.into_inner();
+        if request.table.is_empty() {
+            return Err(Status::invalid_argument("table is required"));
+        }
+        let filter = match request.filter.trim() {
+            "" => None,
+            filter => {
+                SqlFilter::parse(filter)
+                    .map_err(|e| Status::invalid_argument(format!("Invalid filter: {}", e)))?;
+                Some(filter.to_string())
+            }
+        };
+        let event_stream = self.event_stream(&request.source)?;
+
+        let signal_id = snapshot::signal_id();
+        let requested = RequestedSnapshot {
+            signal_id: signal_id.clone(),
+            target: SnapshotTable {
+                table: PublishedTable {
+                    schema: if request.schema.is_empty() {
+                        "public".to_string()
+                    } else {
+                        request.schema
+                    },
+                    table: request.table,
+                },
+                filter,
+            },
+        };
+
+        info!(
+            "Snapshot of {}.{} requested on {}",
+            requested.target.table.schema, requested.target.table.table, event_stream.source
+        );
+
+        // Requests are received by the replication task only when a signal table is configured
+        if event_stream.snapshot_requests.send(requested).is_err() {
+            return Err(Status::failed_precondition(
+                "Snapshots require a running replication stream with --signal-table",
+            ));
+        }
+
+        Ok(Response::new(SnapshotRequestAccepted { signal_id }))
+    }
+}
+
+/// Session state of one source, owned by the task reading client messages
+struct SessionSource {
+    event_stream: EventStream,
+    session_id: u64,
+    cursor_tx: watch::Sender<PgLsn>,
+    seek_tx: watch::Sender<Option<PgLsn>>,
+    consumer_id: Option<String>,
+    /// Set once a hello moved the session to an earlier position
+    resumed: bool,
+    /// Acknowledgements are only tracked for the sources the client subscribed to, so a
+    /// source the client never acknowledges is not held back by it
+    subscribed: bool,
+}
+
+impl SessionSource {
+    fn ack(&self, pg_lsn: PgLsn) {
+        match &self.consumer_id {
+            Some(consumer_id) => self.event_stream.ack_consumer(consumer_id, pg_lsn),
+            None => self.event_stream.ack_session(self.session_id, pg_lsn),
+        }
+        self.cursor_tx.send_if_modified(|cursor| {
+            let advanced = *cursor < pg_lsn;
+            if advanced {
+                *cursor = pg_lsn;
+            }
+            advanced
+        });
+    }
+
+    /// Continue the stream after `cursor` and tell the client where it resumes
+    async fn seek(
+        &mut self,
+        cursor: PgLsn,
+        client_tx: &mpsc::Sender<Result<ServerMessage, Status>>,
+    ) {
+        self.resumed = true;
+        self.cursor_tx.send_replace(cursor);
+        self.seek_tx.send_replace(Some(cursor));
+        let _ = client_tx
+            .send(Ok(ServerMessage {
+                msg: Some(Msg::SessionStart(SessionStartEvent {
+                    resume_lsn: u64::from(cursor),
+                    consumer_id: self.consumer_id.clone().unwrap_or_default(),
+                })),
+                source: self.event_stream.source.clone(),
+            }))
+            .await;
+    }
+
+    /// Continue the source as the named consumer from its cursor
+    async fn attach(
+        &mut self,
+        consumer_id: &str,
+        client_tx: &mpsc::Sender<Result<ServerMessage, Status>>,
+        addr: &str,
+    ) -> Result<(), Status> {
+        let Some(cursor) = self
+            .event_stream
+            .attach_consumer(self.session_id, consumer_id)
+        else {
+            return Err(Status::already_exists(format!(
+                "Consumer '{}' is already connected",
+                consumer_id
+            )));
+        };
+        info!(
+            "Client {} resumes consumer '{}' of {} from {}",
+            addr, consumer_id, self.event_stream.source, cursor
+        );
+        self.consumer_id = Some(consumer_id.to_string());
+        self.seek(cursor, client_tx).await;
+        Ok(())
+    }
+
+    /// Start or stop tracking acknowledgements when the client subscribes to the source or
+    /// leaves it out of its subscription
+    async fn subscribe(
+        &mut self,
+        subscribed: bool,
+        consumer_id: Option<&str>,
+        client_tx: &mpsc::Sender<Result<ServerMessage, Status>>,
+        addr: &str,
+    ) -> Result<(), Status> {
+        if subscribed == self.subscribed {
+            return Ok(());
+        }
+        self.subscribed = subscribed;
+        if !subscribed {
+            self.close();
+            self.consumer_id = None;
+            return Ok(());
+        }
+
+        self.session_id = self.event_stream.register_session();
+        match consumer_id {
+            Some(consumer_id) => self.attach(consumer_id, client_tx, addr).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Stop tracking the acknowledgements of a disconnected client, a consumer keeps its cursor
+    fn close(&self) {
+        match &self.consumer_id {
+            Some(consumer_id) => self.event_stream.detach_consumer(consumer_id),
+            None => self.event_stream.unregister_session(self.session_id),
+        }
+    }
+}
+
+/// Source named in a client message, which may leave it out if there is only one
+fn find_source<'a>(
+    sources: &'a mut [SessionSource],
+    name: &str,
+) -> Result<&'a mut SessionSource, Status> {
+    match (name, sources) {
+        ("", [source]) => Ok(source),
+        ("", _) => Err(Status::invalid_argument(
+            "source is required with several sources",
+        )),
+        (name, sources) => sources
+            .iter_mut()
+            .find(|source| source.event_stream.source == name)
+            .ok_or_else(|| Status::invalid_argument(format!("Unknown source '{}'", name))),
+    }
+}
+
+/// Handle `ClientSubscribe`, acknowledgements are only tracked for the subscribed sources
+async fn subscribe_sources(
+    sources: &mut [SessionSource],
+    subscribe: ClientSubscribe,
+    consumer_id: Option<&str>,
+    filter_tx: &watch::Sender<Option<SubscriptionFilter>>,
+    client_tx: &mpsc::Sender<Result<ServerMessage, Status>>,
+    addr: &str,
+) -> Result<(), Status> {
+    if let Some(name) = subscribe.sources.iter().find(|name| {
+        !sources
+            .iter()
+            .any(|source| source.event_stream.source == **name)
+    }) {
+        return Err(Status::invalid_argument(format!(
+            "Unknown source '{}'",
+            name
+        )));
+    }
+
+    let filter = SubscriptionFilter::new(subscribe).map_err(Status::invalid_argument)?;
+    debug!("Client {} subscribed with {:?}", addr, filter);
+    for source in sources.iter_mut() {
+        let subscribed = filter.includes_source(&source.event_stream.source);
+        source
+            .subscribe(subscribed, consumer_id, client_tx, addr)
+            .await?;
+    }
+    filter_tx.send_replace(Some(filter));
+    Ok(())
+}
+
+/// Handle `ClientHello`: a named consumer continues from its cursor on every subscribed
+/// source, other clients from the LSN they kept themselves on one source
+async fn resume(
+    sources: &mut [SessionSource],
+    consumer_id: &mut Option<String>,
+    hello: ClientHello,
+    client_tx: &mpsc::Sender<Result<ServerMessage, Status>>,
+    addr: &str,
+) -> Result<(), Status> {
+    match (hello.consumer_id.is_empty(), hello.resume_lsn) {
+        (true, 0) => Err(Status::invalid_argument(
+            "consumer_id or resume_lsn is required",
+        )),
+        (false, 0) => {
+            if consumer_id.is_some() || sources.iter().any(|source| source.resumed) {
+                debug!("Client {} sent a second hello, ignored", addr);
+                return Ok(());
+            }
+            *consumer_id = Some(hello.consumer_id.clone());
+            for source in sources.iter_mut().filter(|source| source.subscribed) {
+                source.attach(&hello.consumer_id, client_tx, addr).await?;
+            }
+            Ok(())
+        }
+        (true, resume_lsn) => {
+            let source = find_source(sources, &hello.source)?;
+            if source.resumed {
+                debug!("Client {} sent a second hello, ignored", addr);
+                return Ok(());
+            }
+            let resume_lsn = PgLsn::from(resume_lsn);
+            source
+                .event_stream
+                .resume_session(source.session_id, resume_lsn)
+                .map_err(|earliest_lsn| {
+                    Status::failed_precondition(format!(
+                        "LSN {} is no longer available, earliest is {}",
+                        resume_lsn, earliest_lsn
+                    ))
+                })?;
+            info!(
+                "Client {} resumes {} from {}",
+                addr, source.event_stream.source, resume_lsn
+            );
+            source.seek(resume_lsn, client_tx).await;
+            Ok(())
+        }
+        (false, _) => Err(Status::invalid_argument(
+            "resume_lsn cannot be combined with consumer_id",
+        )),
+    }
+}
+
+/// Sends the events of one source to a session, as the client's settings ask for
+struct Forwarder {
+    source: String,
+    events: EventSource,
+    cursor_rx: watch::Receiver<PgLsn>,
+    seek_rx: watch::Receiver<Option<PgLsn>>,
+    delivery_rx: watch::Receiver<Delivery>,
+    filter_rx: watch::Receiver<Option<SubscriptionFilter>>,
+    credits: watch::Sender<Option<u64>>,
+    client_tx: mpsc::Sender<Result<ServerMessage, Status>>,
+    shutdown_rx: watch::Receiver<()>,
+    transaction_memory_limit: usize,
+}
+
+impl Forwarder {
+    async fn run(self) {
+        let Forwarder {
+            source,
+            mut events,
+            cursor_rx,
+            mut seek_rx,
+            delivery_rx,
+            filter_rx,
+            credits,
+            client_tx,
+            mut shutdown_rx,
+            transaction_memory_limit,
+        } = self;
+
+        let mut batch: Option<TransactionBuffer<RowChange>> = None;
+        let mut replayed = false;
+        loop {
+            tokio::select! {
+                biased;
+                _ = shutdown_rx.changed() => {
+                    debug!("Stopping event forwarder task (client disconnected)");
+                    break;
+                }
+                Ok(()) = seek_rx.changed() => {
+                    let cursor = *seek_rx.borrow_and_update();
+                    if let (EventSource::Log(reader), Some(cursor)) = (&mut events, cursor) {
+                        if !reader.seek(cursor) {
+                            warn!("Event log of {} no longer reaches back to {}", source, cursor);
+                        }
+                        batch = None;
+                        replayed = false;
                     }
-                    msg = broadcast_rx.recv() => {
-                        match msg {
-                            Ok(ev) => {
-                                if client_tx.send(Ok(ev)).await.is_err() {
-                                    info!("Client channel closed — stopping forwarder");
+                }
+                msg = events.recv() => {
+                    match msg {
+                        Ok(ServerMessage { msg: Some(msg), .. }) => {
+                            let cursor = u64::from(*cursor_rx.borrow());
+                            if skip_acknowledged(&mut replayed, &msg, cursor) {
+                                continue;
+                            }
+                            let msg = match filter_rx.borrow().as_ref() {
+                                Some(filter) if !filter.includes_source(&source) => None,
+                                Some(filter) => filter.apply(msg),
+                                None => Some(msg),
+                            };
+                            let Some(msg) = msg else {
+                                continue;
+                            };
+                            let delivery = *delivery_rx.borrow();
+                            let sent = match batch_transaction(
+                                &mut batch,
+                                delivery,
+                                msg,
+                                transaction_memory_limit,
+                            ) {
+                                Ok(Batched::Held) => Ok(true),
+                                Ok(Batched::Event(msg)) => Ok(send_event(
+                                    &source,
+                                    msg,
+                                    &credits,
+                                    &client_tx,
+                                    &mut shutdown_rx,
+                                )
+                                .await),
+                                Ok(Batched::Transaction(chunks)) => {
+                                    send_chunks(
+                                        &source,
+                                        chunks,
+                                        &credits,
+                                        &client_tx,
+                                        &mut shutdown_rx,
+                                    )
+                                    .await
+                                }
+                                Err(e) => Err(e),
+                            };
+                            match sent {
+                                Ok(true) => {}
+                                Ok(false) => break,
+                                Err(e) => {
+                                    error!("Failed to buffer transaction: {}", e);
+                                    let _ = client_tx
+                                        .send(Err(Status::internal(
+                                            "Failed to buffer transaction",
+                                        )))
+                                        .await;
                                     break;
                                 }
                             }
-                            Err(tokio::sync::broadcast::error::RecvError::Closed) => {
-                                error!("Broadcast channel closed");
-                                break;
-                            }
-                            Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
-                                error!("Client lagged behind, skipped {} messages", n);
-                            }
+                        }
+                        Ok(ServerMessage { msg: None, .. }) => {}
+                        Err(RecvError::Closed) => {
+                            error!("Event stream of {} closed", source);
+                            break;
+                        }
+                        Err(RecvError::Lagged(n)) => {
+                            error!("Client lagged behind on {}, skipped {} messages", source, n);
                         }
                     }
                 }
             }
+        }
+    }
+}
+
+/// Send an event once the client has a credit for it, false once the client is gone
+async fn send_event(
+    source: &str,
+    msg: Msg,
+    credits: &watch::Sender<Option<u64>>,
+    client_tx: &mpsc::Sender<Result<ServerMessage, Status>>,
+    shutdown_rx: &mut watch::Receiver<()>,
+) -> bool {
+    let granted = tokio::select! {
+        _ = shutdown_rx.changed() => false,
+        _ = take_credit(credits) => true,
+    };
+    if !granted {
+        debug!("Client disconnected while waiting for credits");
+        return false;
+    }
+    let ev = ServerMessage {
+        msg: Some(msg),
+        source: source.to_string(),
+    };
+    if client_tx.send(Ok(ev)).await.is_err() {
+        info!("Client channel closed — stopping forwarder");
+        return false;
+    }
+    true
+}
+
+/// Send the chunks of a committed transaction, reading its buffered changes as they are sent
+async fn send_chunks(
+    source: &str,
+    chunks: Box<TransactionChunks>,
+    credits: &watch::Sender<Option<u64>>,
+    client_tx: &mpsc::Sender<Result<ServerMessage, Status>>,
+    shutdown_rx: &mut watch::Receiver<()>,
+) -> std::io::Result<bool> {
+    for chunk in chunks {
+        if !send_event(source, chunk?, credits, client_tx, shutdown_rx).await {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Wait until the client requested more events and consume one credit. Sessions that never
+/// sent a request are not limited.
+async fn take_credit(credits: &watch::Sender<Option<u64>>) {
+    let mut credits_rx = credits.subscribe();
+    loop {
+        // Checked and taken at once, the forwarders of other sources share the credits
+        let mut granted = false;
+        credits.send_if_modified(|available| match available {
+            None => {
+                granted = true;
+                false
+            }
+            Some(0) => false,
+            Some(available) => {
+                *available -= 1;
+                granted = true;
+                true
+            }
         });
+        if granted {
+            return;
+        }
+        // Never fails, the sender is borrowed for as long as the receiver lives
+        let _ = credits_rx.changed().await;
+    }
+}
 
-        Ok(Response::new(ReceiverStream::new(client_rx)))
+/// Events of a session, read from the broadcast channel or from the event log at the
+/// session's own position
+enum EventSource {
+    Broadcast {
+        rx: broadcast::Receiver<ServerMessage>,
+        /// Signalled after every event, the channel may have room again
+        received: Arc<Notify>,
+    },
+    Log(LogReader),
+}
+
+impl EventSource {
+    async fn recv(&mut self) -> Result<ServerMessage, RecvError> {
+        match self {
+            EventSource::Broadcast { rx, received } => {
+                let msg = rx.recv().await;
+                received.notify_one();
+                msg
+            }
+            EventSource::Log(reader) => reader.next().await.map_err(|e| {
+                error!("Failed to read event log: {}", e);
+                RecvError::Closed
+            }),
+        }
+    }
+}
+
+/// Whether the session already acknowledged the event, when the replication restarted from an
+/// older position to replay the changes of a reconnected consumer. Whole transactions are
+/// skipped, as the LSNs of their changes may precede earlier commits.
+fn skip_acknowledged(replayed: &mut bool, msg: &Msg, cursor: u64) -> bool {
+    match msg {
+        Msg::Begin(ev) => {
+            *replayed = ev.final_lsn < cursor;
+            *replayed
+        }
+        Msg::BeginPrepare(ev) => {
+            *replayed = ev.prepare_lsn < cursor;
+            *replayed
+        }
+        Msg::Commit(_) | Msg::Prepare(_) => std::mem::take(replayed),
+        Msg::CommitPrepared(ev) => ev.commit_lsn < cursor,
+        Msg::RollbackPrepared(ev) => ev.rollback_end_lsn <= cursor,
+        Msg::LogicalMessage(ev) if !ev.transactional => ev.pg_lsn <= cursor,
+        _ => *replayed,
+    }
+}
+
+/// Event to send after `batch_transaction`
+enum Batched {
+    /// Buffered until the transaction commits
+    Held,
+    Event(Msg),
+    Transaction(Box<TransactionChunks>),
+}
+
+/// Splits a committed transaction into `TransactionEvent`s of at most
+/// `MAX_TRANSACTION_EVENT_BYTES` of changes. A single change larger than that gets a chunk of
+/// its own.
+struct TransactionChunks {
+    commit: CommitEvent,
+    changes: std::iter::Peekable<Drain<RowChange>>,
+    chunk: u32,
+    done: bool,
+}
+
+impl Iterator for TransactionChunks {
+    type Item = std::io::Result<Msg>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut changes = Vec::new();
+        let mut size = 0;
+        while let Some(change) = self.changes.next_if(|change| match change {
+            Ok(change) => {
+                changes.is_empty() || size + change.encoded_len() <= MAX_TRANSACTION_EVENT_BYTES
+            }
+            Err(_) => true,
+        }) {
+            let change = match change {
+                Ok(change) => change,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+            size += change.encoded_len();
+            changes.push(change);
+        }
+        self.done = self.changes.peek().is_none();
+
+        let commit = &self.commit;
+        let chunk = self.chunk;
+        self.chunk += 1;
+        Some(Ok(Msg::Transaction(TransactionEvent {
+            pg_lsn: commit.pg_lsn,
+            xid: commit.xid,
+            commit_lsn: commit.commit_lsn,
+            end_lsn: commit.end_lsn,
+            commit_timestamp: commit.commit_timestamp,
+            changes,
+            chunk,
+            last_chunk: self.done,
+        })))
+    }
+}
+
+/// In transaction delivery mode, buffer row events between Begin and Commit and
+/// return them as `TransactionEvent` chunks once the commit arrives.
+fn batch_transaction(
+    batch: &mut Option<TransactionBuffer<RowChange>>,
+    delivery: Delivery,
+    msg: Msg,
+    memory_limit: usize,
+) -> std::io::Result<Batched> {
+    let change = match msg {
+        Msg::Begin(_) if delivery == Delivery::Transaction => {
+            *batch = Some(TransactionBuffer::new(memory_limit));
+            return Ok(Batched::Held);
+        }
+        Msg::Commit(commit) if batch.is_some() => {
+            let changes = batch.take().unwrap().drain()?;
+            return Ok(Batched::Transaction(Box::new(TransactionChunks {
+                commit,
+                changes: changes.peekable(),
+                chunk: 0,
+                done: false,
+            })));
+        }
+        Msg::Insert(ev) if batch.is_some() => Change::Insert(ev),
+        Msg::Update(ev) if batch.is_some() => Change::Update(ev),
+        Msg::Delete(ev) if batch.is_some() => Change::Delete(ev),
+        Msg::Truncate(ev) if batch.is_some() => Change::Truncate(ev),
+        Msg::LogicalMessage(ev) if batch.is_some() && ev.transactional => Change::Message(ev),
+        msg => return Ok(Batched::Event(msg)),
+    };
+
+    if let Some(buffer) = batch.as_mut() {
+        buffer.push(RowChange {
+            change: Some(change),
+        })?;
     }
+    Ok(Batched::Held)
 }
 
 pub async fn start(
-    event_stream: EventStream,
+    event_streams: Vec<EventStream>,
     port: u32,
     host: String,
+    transaction_memory_limit: usize,
 ) -> Result<(), Box<dyn std::error::Error>> {
     info!("Listening on {}:{}", host, port);
 
-    let plugin_server: PluginServer = PluginServer { event_stream };
+    let plugin_server: PluginServer = PluginServer {
+        event_streams,
+        transaction_memory_limit,
+    };
     Server::builder()
         .add_service(PluginServiceServer::new(plugin_server))
         .serve(format!("{}:{}", host, port).parse()?)
@@ -101,3 +811,203 @@ pub async fn start(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plugin::{BeginEvent, InsertEvent};
+
+    fn insert(payload_size: usize) -> Msg {
+        Msg::Insert(InsertEvent {
+            pg_lsn: 1,
+            schema: "public".to_string(),
+            table: "orders".to_string(),
+            json_payload: "x".repeat(payload_size),
+            xid: 7,
+        })
+    }
+
+    /// Changes per `TransactionEvent` of a transaction of `inserts` rows of `payload_size` bytes
+    fn chunk_sizes(inserts: usize, payload_size: usize) -> Vec<(u32, usize, bool)> {
+        let mut batch = None;
+        let begin = Msg::Begin(BeginEvent::default());
+        let held = batch_transaction(&mut batch, Delivery::Transaction, begin, 1 << 20);
+        assert!(matches!(held, Ok(Batched::Held)));
+        for _ in 0..inserts {
+            let held = batch_transaction(
+                &mut batch,
+                Delivery::Transaction,
+                insert(payload_size),
+                1 << 20,
+            );
+            assert!(matches!(held, Ok(Batched::Held)));
+        }
+
+        let commit = Msg::Commit(CommitEvent {
+            xid: 7,
+            ..Default::default()
+        });
+        let Ok(Batched::Transaction(chunks)) =
+            batch_transaction(&mut batch, Delivery::Transaction, commit, 1 << 20)
+        else {
+            panic!("commit did not release the transaction");
+        };
+        assert!(batch.is_none());
+        chunks
+            .map(|chunk| match chunk.unwrap() {
+                Msg::Transaction(ev) => {
+                    assert_eq!(ev.xid, 7);
+                    (ev.chunk, ev.changes.len(), ev.last_chunk)
+                }
+                msg => panic!("unexpected {:?}", msg),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn small_transaction_is_one_event() {
+        assert_eq!(chunk_sizes(3, 10), vec![(0, 3, true)]);
+        assert_eq!(chunk_sizes(0, 10), vec![(0, 0, true)]);
+    }
+
+    #[test]
+    fn large_transaction_is_split_into_chunks() {
+        // Spilled to disk as well, as it exceeds the memory limit
+        let chunks = chunk_sizes(40, 100 * 1024);
+        assert!(chunks.len() > 1);
+        assert_eq!(
+            chunks.iter().map(|(_, changes, _)| changes).sum::<usize>(),
+            40
+        );
+        for (i, (chunk, changes, last_chunk)) in chunks.iter().enumerate() {
+            assert_eq!(*chunk as usize, i);
+            assert!(*changes * 100 * 1024 <= MAX_TRANSACTION_EVENT_BYTES);
+            assert_eq!(*last_chunk, i == chunks.len() - 1);
+        }
+    }
+
+    #[test]
+    fn oversized_change_gets_a_chunk_of_its_own() {
+        let chunks = chunk_sizes(2, MAX_TRANSACTION_EVENT_BYTES);
+        assert_eq!(chunks, vec![(0, 1, false), (1, 1, true)]);
+    }
+
+    #[test]
+    fn events_pass_through_outside_transaction_delivery() {
+        let mut batch = None;
+        let begin = Msg::Begin(BeginEvent::default());
+        let sent = batch_transaction(&mut batch, Delivery::Event, begin, 1 << 20);
+        assert!(matches!(sent, Ok(Batched::Event(Msg::Begin(_)))));
+        let sent = batch_transaction(&mut batch, Delivery::Event, insert(1), 1 << 20);
+        assert!(matches!(sent, Ok(Batched::Event(Msg::Insert(_)))));
+    }
+
+    #[tokio::test]
+    async fn forwarders_never_take_the_same_credit() {
+        let (credits, _) = watch::channel(Some(1));
+        let first = tokio::spawn({
+            let credits = credits.clone();
+            async move { take_credit(&credits).await }
+        });
+        let second = tokio::spawn({
+            let credits = credits.clone();
+            async move { take_credit(&credits).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(*credits.borrow(), Some(0));
+        assert_eq!(first.is_finished() as u8 + second.is_finished() as u8, 1);
+
+        credits.send_modify(|available| *available = available.map(|n| n + 1));
+        first.await.unwrap();
+        second.await.unwrap();
+        assert_eq!(*credits.borrow(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn sessions_without_requests_are_not_limited() {
+        let (credits, _) = watch::channel(None);
+        for _ in 0..3 {
+            take_credit(&credits).await;
+        }
+        assert_eq!(*credits.borrow(), None);
+    }
+
+    fn session_source(event_stream: &EventStream) -> SessionSource {
+        SessionSource {
+            session_id: event_stream.register_session(),
+            event_stream: event_stream.clone(),
+            cursor_tx: watch::channel(PgLsn::from(0)).0,
+            seek_tx: watch::channel(None).0,
+            consumer_id: None,
+            resumed: false,
+            subscribed: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn unsubscribed_sources_are_not_held_back() {
+        let orders = EventStream::new("orders");
+        let users = EventStream::new("users");
+        let mut sources = [session_source(&orders), session_source(&users)];
+        let (filter_tx, filter_rx) = watch::channel(None);
+        let (client_tx, _client_rx) = mpsc::channel(8);
+        let subscribe = ClientSubscribe {
+            sources: vec!["orders".to_string()],
+            ..Default::default()
+        };
+        subscribe_sources(
+            &mut sources,
+            subscribe,
+            None,
+            &filter_tx,
+            &client_tx,
+            "test",
+        )
+        .await
+        .unwrap();
+        assert!(filter_rx.borrow().is_some());
+
+        // A second session on users acknowledges, the unsubscribed session does not hold it back
+        let other = session_source(&users);
+        other.ack(PgLsn::from(100));
+        assert_eq!(*users.last_ack_lsn.borrow(), PgLsn::from(100));
+        sources[0].ack(PgLsn::from(50));
+        assert_eq!(*orders.last_ack_lsn.borrow(), PgLsn::from(50));
+
+        // Subscribing to users again tracks the session from the current position
+        subscribe_sources(
+            &mut sources,
+            ClientSubscribe::default(),
+            None,
+            &filter_tx,
+            &client_tx,
+            "test",
+        )
+        .await
+        .unwrap();
+        other.ack(PgLsn::from(200));
+        assert_eq!(*users.last_ack_lsn.borrow(), PgLsn::from(100));
+        sources[1].ack(PgLsn::from(200));
+        assert_eq!(*users.last_ack_lsn.borrow(), PgLsn::from(200));
+    }
+
+    #[tokio::test]
+    async fn receiving_from_the_broadcast_channel_signals_room() {
+        let event_stream = EventStream::new("orders");
+        let mut events = EventSource::Broadcast {
+            rx: event_stream.tx.subscribe(),
+            received: event_stream.received.clone(),
+        };
+        event_stream.tx.send(ServerMessage::default()).unwrap();
+        assert_eq!(event_stream.tx.len(), 1);
+
+        events.recv().await.unwrap();
+        assert_eq!(event_stream.tx.len(), 0);
+        tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            event_stream.received.notified(),
+        )
+        .await
+        .expect("the paused stream is woken up");
+    }
+}
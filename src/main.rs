@@ -1,9 +1,19 @@
 mod cli;
+mod config;
+mod decode;
+mod event_log;
 mod event_stream;
+mod expression;
+mod filter;
 mod grpc;
+mod pgoutput;
 mod postgres;
+mod publication;
+mod snapshot;
+mod source;
+mod transaction_buffer;
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches};
 use tracing_subscriber::{EnvFilter, fmt};
 
 #[tokio::main]
@@ -15,11 +25,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with_target(true)
         .init();
 
-    let args = cli::Cli::parse();
+    let matches = cli::Cli::command().get_matches();
+    let args = cli::Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
-    let event_stream = event_stream::EventStream::new();
-    let replication_instance =
-        postgres::ReplicationInstance::new(event_stream.clone(), args.connection.clone());
+    let config = match (&args.command, &args.config) {
+        (cli::Commands::Config { .. }, _) | (_, None) => config::Config::default(),
+        (_, Some(path)) => config::Config::load(std::path::Path::new(path)).unwrap_or_else(|e| {
+            eprintln!("Invalid config {}:\n{}", path, e);
+            std::process::exit(1);
+        }),
+    };
+    let connection = || {
+        args.connection
+            .clone()
+            .or_else(|| config.connection.clone())
+            .ok_or("--connection is required")
+    };
 
     match args.command {
         cli::Commands::Start {
@@ -28,21 +49,257 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             temporary,
             port,
             host,
+            transaction_memory_limit,
+            streaming,
+            two_phase,
+            messages,
+            lossless,
+            binary,
+            snapshot,
+            signal_table,
+            snapshot_chunk_size,
+            data_dir,
+            log_dir,
+            log_segment_size,
+            log_retention_bytes,
+            log_retention_hours,
             help: _,
         } => {
+            // Flags left at their defaults are taken from the config file
+            let start = matches.subcommand_matches("start").unwrap();
+            let replication = &config.replication;
+            let temporary = config::setting(start, "temporary", temporary, replication.temporary);
+            let port = config::setting(start, "port", port, config.server.port);
+            let host = config::setting(start, "host", host, config.server.host.clone());
+            let transaction_memory_limit = config::setting(
+                start,
+                "transaction_memory_limit",
+                transaction_memory_limit,
+                config.transaction_memory_limit,
+            );
+            let streaming = config::setting(start, "streaming", streaming, replication.streaming);
+            let two_phase = config::setting(start, "two_phase", two_phase, replication.two_phase);
+            let messages = config::setting(start, "messages", messages, replication.messages);
+            let lossless = config::setting(start, "lossless", lossless, replication.lossless);
+            let binary = config::setting(start, "binary", binary, replication.binary);
+            let snapshot = config::setting(start, "snapshot", snapshot, replication.snapshot);
+            let signal_table = config::setting(
+                start,
+                "signal_table",
+                signal_table,
+                replication.signal_table.clone(),
+            );
+            let snapshot_chunk_size = config::setting(
+                start,
+                "snapshot_chunk_size",
+                snapshot_chunk_size,
+                replication.snapshot_chunk_size,
+            );
+            let data_dir = config::setting(start, "data_dir", data_dir, config.data_dir.clone());
+            let log_dir = config::setting(start, "log_dir", log_dir, config.log.dir.clone());
+            let log_segment_size = config::setting(
+                start,
+                "log_segment_size",
+                log_segment_size,
+                config.log.segment_size,
+            );
+            let log_retention_bytes = config::setting(
+                start,
+                "log_retention_bytes",
+                log_retention_bytes,
+                config.log.retention_bytes,
+            );
+            let log_retention_hours = config::setting(
+                start,
+                "log_retention_hours",
+                log_retention_hours,
+                config.log.retention_hours,
+            );
+
+            let options = postgres::ReplicationOptions {
+                streaming: streaming.unwrap(),
+                two_phase: two_phase.unwrap(),
+                messages: messages.unwrap(),
+                binary: binary.unwrap(),
+                snapshot: snapshot.unwrap(),
+                signal_table: signal_table.map(|name| snapshot::SignalTable {
+                    table: snapshot::PublishedTable::parse(&name),
+                    chunk_size: snapshot_chunk_size.unwrap(),
+                }),
+                transaction_memory_limit: transaction_memory_limit.unwrap(),
+                lossless: lossless.unwrap(),
+            };
+
+            // Sources come from one place, rather than one silently overriding the other
+            if !config.sources.is_empty() && (publication.is_some() || slot.is_some()) {
+                return Err(
+                    "PUBLICATION and SLOT can't be given when the config file lists sources".into(),
+                );
+            }
+            let single_source = config.sources.is_empty();
+            let sources = if let (Some(publication), Some(slot)) = (publication, slot) {
+                vec![source::Source {
+                    name: slot.clone(),
+                    connection: connection()?,
+                    publication,
+                    slot,
+                    temporary: temporary.unwrap(),
+                    options,
+                }]
+            } else if !single_source {
+                source::Source::resolve(
+                    config.sources.clone(),
+                    connection().ok().as_deref(),
+                    temporary.unwrap(),
+                    &options,
+                    snapshot_chunk_size.unwrap(),
+                )?
+            } else {
+                return Err(
+                    "PUBLICATION and SLOT are required unless the config file lists sources".into(),
+                );
+            };
+            // Configured sources keep their cursors and logs in directories of their own
+            let source_dir = |dir: &str, source: &source::Source| {
+                if single_source {
+                    std::path::PathBuf::from(dir)
+                } else {
+                    std::path::Path::new(dir).join(&source.name)
+                }
+            };
+
+            let mut event_streams = Vec::with_capacity(sources.len());
+            for source in &sources {
+                let event_stream = event_stream::EventStream::new(&source.name);
+                if let Some(data_dir) = &data_dir {
+                    event_stream.load_consumers(&source_dir(data_dir, source))?;
+                }
+                if let Some(log_dir) = &log_dir {
+                    event_stream.open_log(
+                        &source_dir(log_dir, source),
+                        event_log::EventLogOptions {
+                            segment_size: log_segment_size.unwrap(),
+                            retention_bytes: log_retention_bytes.unwrap(),
+                            // Hours beyond any practical retention keep segments forever
+                            retention: std::time::Duration::from_secs(
+                                log_retention_hours.unwrap().saturating_mul(3600),
+                            ),
+                        },
+                    )?;
+                }
+                event_streams.push(event_stream);
+            }
+
+            let replication = sources.into_iter().zip(event_streams.clone()).map(
+                |(source, event_stream)| async move {
+                    postgres::ReplicationInstance::new(event_stream, source.connection)
+                        .start(
+                            &source.publication,
+                            &source.slot,
+                            source.temporary,
+                            source.options,
+                        )
+                        .await
+                },
+            );
+
             tokio::select! {
-                res = grpc::start(event_stream.clone(), port.unwrap(), host.unwrap()) => res,
-                res = replication_instance.start(publication.as_str(), slot.as_str(),temporary.unwrap()) => Ok(res),
+                res = grpc::start(
+                    event_streams,
+                    port.unwrap(),
+                    host.unwrap(),
+                    transaction_memory_limit.unwrap(),
+                ) => res,
+                _ = futures::future::join_all(replication) => Ok(()),
             }?;
         }
         cli::Commands::List { help: _ } => {
-            replication_instance.list_replication_slots().await?;
+            postgres::ReplicationInstance::new(event_stream::EventStream::new(""), connection()?)
+                .list_replication_slots()
+                .await?;
         }
         cli::Commands::Delete { slot, help: _ } => {
-            replication_instance
+            postgres::ReplicationInstance::new(event_stream::EventStream::new(""), connection()?)
                 .delete_replication_slot(slot.as_str())
                 .await?;
         }
+        cli::Commands::Config {
+            command: cli::ConfigCommands::Validate { file, help: _ },
+            help: _,
+        } => {
+            let path = file
+                .or(args.config.clone())
+                .ok_or("FILE or --config is required")?;
+            match config::Config::load(std::path::Path::new(&path)) {
+                Ok(_) => println!("{} is valid", path),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        cli::Commands::Publication { command, help: _ } => {
+            let publications = publication::Publications::connect(&connection()?).await?;
+
+            match command {
+                cli::PublicationCommands::Create {
+                    publication,
+                    tables,
+                    all_tables,
+                    filter,
+                    columns,
+                    help: _,
+                } => {
+                    if tables.is_empty() && (filter.is_some() || !columns.is_empty()) {
+                        return Err("--where and --columns need at least one table".into());
+                    }
+                    let tables: Vec<_> = tables
+                        .iter()
+                        .map(|table| {
+                            publication::PublicationTable::new(
+                                table,
+                                filter.clone(),
+                                columns.clone(),
+                            )
+                        })
+                        .collect();
+                    publications
+                        .create(&publication, &tables, all_tables.unwrap())
+                        .await?;
+                }
+                cli::PublicationCommands::Alter {
+                    publication,
+                    command,
+                    help: _,
+                } => match command {
+                    cli::AlterPublicationCommands::AddTable {
+                        table,
+                        filter,
+                        columns,
+                        help: _,
+                    } => {
+                        let table = publication::PublicationTable::new(&table, filter, columns);
+                        publications.add_table(&publication, &table).await?;
+                    }
+                    cli::AlterPublicationCommands::DropTable { table, help: _ } => {
+                        let table = snapshot::PublishedTable::parse(&table);
+                        publications.drop_table(&publication, &table).await?;
+                    }
+                },
+                cli::PublicationCommands::Drop {
+                    publication,
+                    help: _,
+                } => {
+                    publications.drop(&publication).await?;
+                }
+                cli::PublicationCommands::Show {
+                    publication,
+                    help: _,
+                } => {
+                    publications.show(publication.as_deref()).await?;
+                }
+            }
+        }
     }
 
     Ok(())
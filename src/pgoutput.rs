@@ -0,0 +1,508 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use postgres_replication::protocol::LogicalReplicationMessage;
+use std::io;
+
+// Messages added in pgoutput protocol version 2, which postgres-replication does not decode
+const STREAM_START_TAG: u8 = b'S';
+const STREAM_STOP_TAG: u8 = b'E';
+const STREAM_COMMIT_TAG: u8 = b'c';
+const STREAM_ABORT_TAG: u8 = b'A';
+
+// Logical decoding message emitted via pg_logical_emit_message()
+const MESSAGE_TAG: u8 = b'M';
+
+// Messages added in pgoutput protocol version 3
+const BEGIN_PREPARE_TAG: u8 = b'b';
+const PREPARE_TAG: u8 = b'P';
+const COMMIT_PREPARED_TAG: u8 = b'K';
+const ROLLBACK_PREPARED_TAG: u8 = b'r';
+const STREAM_PREPARE_TAG: u8 = b'p';
+
+#[derive(Debug)]
+pub enum PgOutputMessage {
+    Logical(LogicalReplicationMessage),
+    Message(MessageBody),
+    /// Change of a streamed in-progress transaction, still prefixed with its (sub)transaction xid
+    Streamed {
+        subxid: u32,
+        data: Bytes,
+    },
+    StreamStart {
+        xid: u32,
+        first_segment: bool,
+    },
+    StreamStop,
+    StreamCommit {
+        xid: u32,
+        commit_lsn: u64,
+        end_lsn: u64,
+        timestamp: i64,
+    },
+    StreamAbort {
+        xid: u32,
+        subxid: u32,
+    },
+    BeginPrepare(PrepareBody),
+    Prepare(PrepareBody),
+    StreamPrepare(PrepareBody),
+    CommitPrepared {
+        xid: u32,
+        gid: String,
+        commit_lsn: u64,
+        end_lsn: u64,
+        timestamp: i64,
+    },
+    RollbackPrepared {
+        xid: u32,
+        gid: String,
+        prepare_end_lsn: u64,
+        rollback_end_lsn: u64,
+        prepare_timestamp: i64,
+        rollback_timestamp: i64,
+    },
+}
+
+#[derive(Debug)]
+pub struct MessageBody {
+    pub transactional: bool,
+    pub lsn: u64,
+    pub prefix: String,
+    pub content: Bytes,
+}
+
+/// Body shared by the Begin Prepare, Prepare and Stream Prepare messages
+#[derive(Debug)]
+pub struct PrepareBody {
+    pub xid: u32,
+    pub gid: String,
+    pub prepare_lsn: u64,
+    pub end_lsn: u64,
+    pub timestamp: i64,
+}
+
+/// Change of a streamed transaction held back until the transaction commits
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StreamedChange {
+    #[prost(uint64, tag = "1")]
+    pub lsn: u64,
+    #[prost(uint32, tag = "2")]
+    pub subxid: u32,
+    #[prost(bytes = "bytes", tag = "3")]
+    pub data: Bytes,
+}
+
+/// Decodes pgoutput messages, keeping track of whether a streamed transaction block is open
+#[derive(Debug, Default)]
+pub struct PgOutputDecoder {
+    in_stream: bool,
+}
+
+impl PgOutputDecoder {
+    pub fn decode(&mut self, data: &Bytes) -> io::Result<PgOutputMessage> {
+        let mut buf = data.clone();
+        let tag = read_u8(&mut buf)?;
+
+        let message = match tag {
+            STREAM_START_TAG => {
+                self.in_stream = true;
+                PgOutputMessage::StreamStart {
+                    xid: read_u32(&mut buf)?,
+                    first_segment: read_u8(&mut buf)? == 1,
+                }
+            }
+            STREAM_STOP_TAG => {
+                self.in_stream = false;
+                PgOutputMessage::StreamStop
+            }
+            STREAM_COMMIT_TAG => {
+                let xid = read_u32(&mut buf)?;
+                let _flags = read_u8(&mut buf)?;
+                PgOutputMessage::StreamCommit {
+                    xid,
+                    commit_lsn: read_u64(&mut buf)?,
+                    end_lsn: read_u64(&mut buf)?,
+                    timestamp: read_u64(&mut buf)? as i64,
+                }
+            }
+            STREAM_ABORT_TAG => PgOutputMessage::StreamAbort {
+                xid: read_u32(&mut buf)?,
+                subxid: read_u32(&mut buf)?,
+            },
+            BEGIN_PREPARE_TAG => PgOutputMessage::BeginPrepare(read_prepare(&mut buf)?),
+            PREPARE_TAG => {
+                let _flags = read_u8(&mut buf)?;
+                PgOutputMessage::Prepare(read_prepare(&mut buf)?)
+            }
+            STREAM_PREPARE_TAG => {
+                let _flags = read_u8(&mut buf)?;
+                PgOutputMessage::StreamPrepare(read_prepare(&mut buf)?)
+            }
+            COMMIT_PREPARED_TAG => {
+                let _flags = read_u8(&mut buf)?;
+                let commit_lsn = read_u64(&mut buf)?;
+                let end_lsn = read_u64(&mut buf)?;
+                let timestamp = read_u64(&mut buf)? as i64;
+                PgOutputMessage::CommitPrepared {
+                    xid: read_u32(&mut buf)?,
+                    gid: read_cstr(&mut buf)?,
+                    commit_lsn,
+                    end_lsn,
+                    timestamp,
+                }
+            }
+            ROLLBACK_PREPARED_TAG => {
+                let _flags = read_u8(&mut buf)?;
+                let prepare_end_lsn = read_u64(&mut buf)?;
+                let rollback_end_lsn = read_u64(&mut buf)?;
+                let prepare_timestamp = read_u64(&mut buf)? as i64;
+                let rollback_timestamp = read_u64(&mut buf)? as i64;
+                PgOutputMessage::RollbackPrepared {
+                    xid: read_u32(&mut buf)?,
+                    gid: read_cstr(&mut buf)?,
+                    prepare_end_lsn,
+                    rollback_end_lsn,
+                    prepare_timestamp,
+                    rollback_timestamp,
+                }
+            }
+            _ if self.in_stream => PgOutputMessage::Streamed {
+                subxid: read_u32(&mut buf)?,
+                data: data.clone(),
+            },
+            _ => decode_unstreamed(data)?,
+        };
+
+        Ok(message)
+    }
+}
+
+/// Decode a change or logical decoding message that is not prefixed with an xid
+fn decode_unstreamed(data: &Bytes) -> io::Result<PgOutputMessage> {
+    if data.first() != Some(&MESSAGE_TAG) {
+        return Ok(PgOutputMessage::Logical(LogicalReplicationMessage::parse(
+            data,
+        )?));
+    }
+
+    let mut buf = data.slice(1..);
+    let transactional = read_u8(&mut buf)? == 1;
+    let lsn = read_u64(&mut buf)?;
+    let prefix = read_cstr(&mut buf)?;
+    let len = read_u32(&mut buf)? as usize;
+    if buf.remaining() < len {
+        return Err(truncated());
+    }
+
+    Ok(PgOutputMessage::Message(MessageBody {
+        transactional,
+        lsn,
+        prefix,
+        content: buf.split_to(len),
+    }))
+}
+
+/// Parse a change of a streamed transaction by dropping the xid that follows the message tag
+pub fn parse_streamed(data: &Bytes) -> io::Result<PgOutputMessage> {
+    if data.len() < 5 {
+        return Err(truncated());
+    }
+
+    let mut stripped = BytesMut::with_capacity(data.len() - 4);
+    stripped.put_u8(data[0]);
+    stripped.extend_from_slice(&data[5..]);
+
+    decode_unstreamed(&stripped.freeze())
+}
+
+fn read_prepare(buf: &mut Bytes) -> io::Result<PrepareBody> {
+    let prepare_lsn = read_u64(buf)?;
+    let end_lsn = read_u64(buf)?;
+    let timestamp = read_u64(buf)? as i64;
+    Ok(PrepareBody {
+        xid: read_u32(buf)?,
+        gid: read_cstr(buf)?,
+        prepare_lsn,
+        end_lsn,
+        timestamp,
+    })
+}
+
+fn read_cstr(buf: &mut Bytes) -> io::Result<String> {
+    let end = buf.iter().position(|&b| b == 0).ok_or_else(truncated)?;
+    let s = String::from_utf8_lossy(&buf[..end]).into_owned();
+    buf.advance(end + 1);
+    Ok(s)
+}
+
+fn read_u8(buf: &mut Bytes) -> io::Result<u8> {
+    if buf.remaining() < 1 {
+        return Err(truncated());
+    }
+    Ok(buf.get_u8())
+}
+
+fn read_u32(buf: &mut Bytes) -> io::Result<u32> {
+    if buf.remaining() < 4 {
+        return Err(truncated());
+    }
+    Ok(buf.get_u32())
+}
+
+fn read_u64(buf: &mut Bytes) -> io::Result<u64> {
+    if buf.remaining() < 8 {
+        return Err(truncated());
+    }
+    Ok(buf.get_u64())
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "truncated pgoutput message")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(tag: u8, body: impl FnOnce(&mut BytesMut)) -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_u8(tag);
+        body(&mut buf);
+        buf.freeze()
+    }
+
+    #[test]
+    fn decodes_stream_blocks() {
+        let mut decoder = PgOutputDecoder::default();
+        let start = message(STREAM_START_TAG, |buf| {
+            buf.put_u32(740);
+            buf.put_u8(1);
+        });
+        let PgOutputMessage::StreamStart { xid, first_segment } = decoder.decode(&start).unwrap()
+        else {
+            panic!("expected a stream start");
+        };
+        assert_eq!((xid, first_segment), (740, true));
+
+        // Changes inside the block are prefixed with the xid of their subtransaction
+        let insert = message(b'I', |buf| {
+            buf.put_u32(741);
+            buf.put_u32(16384);
+        });
+        let PgOutputMessage::Streamed { subxid, data } = decoder.decode(&insert).unwrap() else {
+            panic!("expected a streamed change");
+        };
+        assert_eq!(subxid, 741);
+        assert_eq!(data, insert);
+
+        let stop = message(STREAM_STOP_TAG, |_| {});
+        assert!(matches!(
+            decoder.decode(&stop).unwrap(),
+            PgOutputMessage::StreamStop
+        ));
+        assert!(!decoder.in_stream);
+    }
+
+    #[test]
+    fn decodes_stream_commit_and_abort() {
+        let mut decoder = PgOutputDecoder::default();
+        let commit = message(STREAM_COMMIT_TAG, |buf| {
+            buf.put_u32(740);
+            buf.put_u8(0);
+            buf.put_u64(0x1_6B37_4D48);
+            buf.put_u64(0x1_6B37_4D80);
+            buf.put_u64(782_000_000);
+        });
+        let PgOutputMessage::StreamCommit {
+            xid,
+            commit_lsn,
+            end_lsn,
+            timestamp,
+        } = decoder.decode(&commit).unwrap()
+        else {
+            panic!("expected a stream commit");
+        };
+        assert_eq!(xid, 740);
+        assert_eq!(commit_lsn, 0x1_6B37_4D48);
+        assert_eq!(end_lsn, 0x1_6B37_4D80);
+        assert_eq!(timestamp, 782_000_000);
+
+        let abort = message(STREAM_ABORT_TAG, |buf| {
+            buf.put_u32(740);
+            buf.put_u32(742);
+        });
+        let PgOutputMessage::StreamAbort { xid, subxid } = decoder.decode(&abort).unwrap() else {
+            panic!("expected a stream abort");
+        };
+        assert_eq!((xid, subxid), (740, 742));
+    }
+
+    #[test]
+    fn rejects_truncated_stream_messages() {
+        let mut decoder = PgOutputDecoder::default();
+        for data in [
+            Bytes::new(),
+            message(STREAM_START_TAG, |buf| buf.put_u32(740)),
+            message(STREAM_COMMIT_TAG, |buf| {
+                buf.put_u32(740);
+                buf.put_u8(0);
+                buf.put_u64(1);
+            }),
+            message(STREAM_ABORT_TAG, |buf| buf.put_u32(740)),
+        ] {
+            let e = decoder.decode(&data).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        }
+
+        decoder.in_stream = true;
+        let e = decoder
+            .decode(&message(b'I', |buf| buf.put_u16(1)))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(parse_streamed(&Bytes::from_static(b"I\0\0\0")).is_err());
+    }
+
+    fn put_prepare(buf: &mut BytesMut, gid: &str) {
+        buf.put_u64(0x3000);
+        buf.put_u64(0x3080);
+        buf.put_u64(782_000_000);
+        buf.put_u32(750);
+        buf.put_slice(gid.as_bytes());
+        buf.put_u8(0);
+    }
+
+    #[test]
+    fn decodes_prepare_messages() {
+        let mut decoder = PgOutputDecoder::default();
+        let begin = message(BEGIN_PREPARE_TAG, |buf| put_prepare(buf, "tx-1"));
+        let prepare = message(PREPARE_TAG, |buf| {
+            buf.put_u8(0);
+            put_prepare(buf, "tx-1");
+        });
+        let stream_prepare = message(STREAM_PREPARE_TAG, |buf| {
+            buf.put_u8(0);
+            put_prepare(buf, "tx-1");
+        });
+
+        for data in [begin, prepare, stream_prepare] {
+            let (PgOutputMessage::BeginPrepare(body)
+            | PgOutputMessage::Prepare(body)
+            | PgOutputMessage::StreamPrepare(body)) = decoder.decode(&data).unwrap()
+            else {
+                panic!("expected a prepare message");
+            };
+            assert_eq!(body.xid, 750);
+            assert_eq!(body.gid, "tx-1");
+            assert_eq!((body.prepare_lsn, body.end_lsn), (0x3000, 0x3080));
+            assert_eq!(body.timestamp, 782_000_000);
+        }
+    }
+
+    #[test]
+    fn decodes_commit_and_rollback_prepared() {
+        let mut decoder = PgOutputDecoder::default();
+        let commit = message(COMMIT_PREPARED_TAG, |buf| {
+            buf.put_u8(0);
+            buf.put_u64(0x4000);
+            buf.put_u64(0x4080);
+            buf.put_u64(783_000_000);
+            buf.put_u32(750);
+            buf.put_slice(b"tx-1\0");
+        });
+        let PgOutputMessage::CommitPrepared {
+            xid,
+            gid,
+            commit_lsn,
+            end_lsn,
+            timestamp,
+        } = decoder.decode(&commit).unwrap()
+        else {
+            panic!("expected a commit prepared");
+        };
+        assert_eq!((xid, gid.as_str()), (750, "tx-1"));
+        assert_eq!(
+            (commit_lsn, end_lsn, timestamp),
+            (0x4000, 0x4080, 783_000_000)
+        );
+
+        let rollback = message(ROLLBACK_PREPARED_TAG, |buf| {
+            buf.put_u8(0);
+            buf.put_u64(0x3080);
+            buf.put_u64(0x4080);
+            buf.put_u64(782_000_000);
+            buf.put_u64(783_000_000);
+            buf.put_u32(750);
+            buf.put_slice(b"tx-1\0");
+        });
+        let PgOutputMessage::RollbackPrepared {
+            xid,
+            gid,
+            prepare_end_lsn,
+            rollback_end_lsn,
+            prepare_timestamp,
+            rollback_timestamp,
+        } = decoder.decode(&rollback).unwrap()
+        else {
+            panic!("expected a rollback prepared");
+        };
+        assert_eq!((xid, gid.as_str()), (750, "tx-1"));
+        assert_eq!((prepare_end_lsn, rollback_end_lsn), (0x3080, 0x4080));
+        assert_eq!(
+            (prepare_timestamp, rollback_timestamp),
+            (782_000_000, 783_000_000)
+        );
+    }
+
+    #[test]
+    fn rejects_a_gid_without_terminator() {
+        let mut decoder = PgOutputDecoder::default();
+        let mut data =
+            BytesMut::from(&message(BEGIN_PREPARE_TAG, |buf| put_prepare(buf, "tx-1"))[..]);
+        data.truncate(data.len() - 1);
+        let e = decoder.decode(&data.freeze()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    fn logical_message(transactional: bool, prefix: &str, content: &[u8]) -> Bytes {
+        message(MESSAGE_TAG, |buf| {
+            buf.put_u8(transactional as u8);
+            buf.put_u64(0x5000);
+            buf.put_slice(prefix.as_bytes());
+            buf.put_u8(0);
+            buf.put_u32(content.len() as u32);
+            buf.put_slice(content);
+        })
+    }
+
+    #[test]
+    fn decodes_logical_messages() {
+        let mut decoder = PgOutputDecoder::default();
+        let data = logical_message(false, "audit", b"{\"user\":1}");
+        let PgOutputMessage::Message(body) = decoder.decode(&data).unwrap() else {
+            panic!("expected a logical message");
+        };
+        assert!(!body.transactional);
+        assert_eq!(body.lsn, 0x5000);
+        assert_eq!(body.prefix, "audit");
+        assert_eq!(&body.content[..], b"{\"user\":1}");
+
+        // In a streamed transaction the message is prefixed with the xid
+        let mut streamed = BytesMut::new();
+        streamed.put_u8(MESSAGE_TAG);
+        streamed.put_u32(741);
+        streamed.extend_from_slice(&logical_message(true, "audit", b"")[1..]);
+        let PgOutputMessage::Message(body) = parse_streamed(&streamed.freeze()).unwrap() else {
+            panic!("expected a logical message");
+        };
+        assert!(body.transactional);
+        assert_eq!(body.prefix, "audit");
+        assert!(body.content.is_empty());
+    }
+
+    #[test]
+    fn rejects_content_beyond_the_message() {
+        let mut decoder = PgOutputDecoder::default();
+        let data = logical_message(false, "audit", b"payload");
+        let e = decoder.decode(&data.slice(..data.len() - 1)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -1,19 +1,33 @@
-use crate::event_stream::EventStream;
+use crate::decode;
+use crate::event_stream::{EVENT_BUFFER_CAPACITY, EventStream, RequestedSnapshot};
 use crate::grpc::plugin::{
-    self, DeleteEvent, InsertEvent, ServerMessage, TruncateEvent, UpdateEvent,
+    self, BeginEvent, BeginPrepareEvent, ColumnSchema, CommitEvent, CommitPreparedEvent,
+    DeleteEvent, InsertEvent, LogicalMessageEvent, PrepareEvent, RollbackPreparedEvent,
+    SchemaEvent, ServerMessage, SnapshotEvent, SnapshotProgressEvent, SnapshotStage, TruncateEvent,
+    UpdateEvent,
 };
+use crate::pgoutput::{
+    self, MessageBody, PgOutputDecoder, PgOutputMessage, PrepareBody, StreamedChange,
+};
+use crate::snapshot::{
+    self, IncrementalSnapshot, Signal, SignalTable, SnapshotWindow, TableCursor,
+};
+use crate::transaction_buffer::{Drain, TransactionBuffer};
 use futures::StreamExt;
-use postgres_replication::LogicalReplicationStream;
+use postgres_replication::ReplicationStream;
 use postgres_replication::protocol::{
-    LogicalReplicationMessage, ReplicationMessage, Tuple, TupleData,
+    LogicalReplicationMessage, ReplicaIdentity, ReplicationMessage, Tuple, TupleData,
 };
 use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
-use std::sync::LazyLock;
+use std::pin::Pin;
+use std::sync::{LazyLock, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, watch};
 use tokio_postgres::types::PgLsn;
 use tokio_postgres::{Client, NoTls, SimpleQueryMessage};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 /// Number of seconds between Unix epoch (1970-01-01) and Postgres epoch (2000-01-01)
 const POSTGRES_EPOCH_OFFSET_SECONDS: u64 = 946_684_800;
@@ -22,18 +36,103 @@ const POSTGRES_EPOCH_OFFSET_SECONDS: u64 = 946_684_800;
 static POSTGRES_EPOCH: LazyLock<SystemTime> =
     LazyLock::new(|| UNIX_EPOCH + Duration::from_secs(POSTGRES_EPOCH_OFFSET_SECONDS));
 
-#[derive(Debug)]
+/// Column flag set by pgoutput for columns that are part of the replica identity
+const COLUMN_FLAG_KEY: i8 = 1;
+
+/// Queued events below which a paused stream resumes reading in lossless mode
+const LOSSLESS_RESUME_LEN: usize = EVENT_BUFFER_CAPACITY / 2;
+
+#[derive(Debug, PartialEq)]
 pub struct RelationInfo {
     pub schema: String,
     pub table: String,
-    pub columns: Vec<String>,
+    pub replica_identity: plugin::ReplicaIdentity,
+    pub columns: Vec<ColumnInfo>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ColumnInfo {
+    pub name: String,
+    pub flags: i8,
+    pub type_oid: u32,
+    /// Type the values are decoded as, differs from `type_oid` for enums and domains
+    pub decode_oid: u32,
+    pub type_modifier: i32,
+}
+
+impl ColumnInfo {
+    pub fn is_key(&self) -> bool {
+        self.flags & COLUMN_FLAG_KEY != 0
+    }
+}
+
+/// Options for the replication stream
+#[derive(Debug, Clone)]
+pub struct ReplicationOptions {
+    /// Stream large in-progress transactions (pgoutput protocol version 2)
+    pub streaming: bool,
+    /// Decode prepared transactions at PREPARE time (pgoutput protocol version 3)
+    pub two_phase: bool,
+    /// Forward messages emitted with pg_logical_emit_message()
+    pub messages: bool,
+    /// Receive column values in binary format
+    pub binary: bool,
+    /// Send the existing rows of the published tables when creating the replication slot
+    pub snapshot: bool,
+    /// Table for incremental snapshot signals and watermarks
+    pub signal_table: Option<SignalTable>,
+    /// Memory for buffering a streamed transaction before spilling to disk
+    pub transaction_memory_limit: usize,
+    /// Pause reading the stream instead of dropping events for slow clients
+    pub lossless: bool,
+}
+
+impl ReplicationOptions {
+    /// pgoutput options for START_REPLICATION
+    fn plugin_options(&self, publication: &str) -> String {
+        let proto_version = if self.two_phase {
+            3
+        } else if self.streaming {
+            2
+        } else {
+            1
+        };
+
+        let mut options = vec![format!("proto_version '{}'", proto_version)];
+        if self.streaming {
+            options.push("streaming 'on'".to_string());
+        }
+        if self.two_phase {
+            options.push("two_phase 'on'".to_string());
+        }
+        if self.messages {
+            options.push("messages 'true'".to_string());
+        }
+        if self.binary {
+            options.push("binary 'true'".to_string());
+        }
+        options.push(format!("publication_names '{}'", publication));
+
+        options.join(", ")
+    }
 }
 
 pub struct ReplicationInstance {
     event_stream: EventStream,
     connection_string: String,
+    relation_store: HashMap<u32, RelationInfo>,
     last_ack_lsn_sent: PgLsn,
     last_status_update: SystemTime,
+    signal_table: Option<SignalTable>,
+    incremental_snapshot: Option<IncrementalSnapshot>,
+    snapshot_window: Option<SnapshotWindow>,
+    lossless: bool,
+    /// Snapshot values are read in binary format like the stream's
+    binary: bool,
+    /// Events held back in lossless mode until the slowest client has room for them. The stream
+    /// is paused while any are held back, so they are bounded by the events of one replication
+    /// message: a chunk of a snapshot, as a released streamed transaction waits between changes.
+    pending: Mutex<VecDeque<(u64, plugin::server_message::Msg)>>,
 }
 
 impl ReplicationInstance {
@@ -41,8 +140,15 @@ impl ReplicationInstance {
         Self {
             event_stream,
             connection_string,
+            relation_store: HashMap::new(),
             last_ack_lsn_sent: PgLsn::from(0),
             last_status_update: SystemTime::now(),
+            signal_table: None,
+            incremental_snapshot: None,
+            snapshot_window: None,
+            lossless: false,
+            binary: false,
+            pending: Mutex::new(VecDeque::new()),
         }
     }
 
@@ -51,11 +157,14 @@ impl ReplicationInstance {
         client: &Client,
         replication_slot: &str,
         temporary: bool,
-    ) -> Result<(String, PgLsn), Box<dyn Error>> {
-        // Query existing replication slot
+        two_phase: bool,
+        export_snapshot: bool,
+    ) -> Result<(String, PgLsn, Option<String>), Box<dyn Error>> {
+        // Query existing replication slot. Changes up to confirmed_flush_lsn were acknowledged,
+        // restart_lsn is only where decoding has to begin to reassemble open transactions.
         let existing_slots = client
             .simple_query(&format!(
-                "SELECT slot_name, restart_lsn FROM pg_replication_slots WHERE slot_name = '{}'",
+                "SELECT slot_name, confirmed_flush_lsn FROM pg_replication_slots WHERE slot_name = '{}'",
                 replication_slot
             ))
             .await?;
@@ -78,38 +187,78 @@ impl ReplicationInstance {
                 if temporary { "temporary" } else { "permanent" }
             );
 
-            let create_result = client
+            if export_snapshot {
+                return self
+                    .create_replication_slot_with_snapshot(
+                        client,
+                        replication_slot,
+                        temporary,
+                        two_phase,
+                    )
+                    .await;
+            }
+
+            // Newly created replication slot starts at the returned consistent point
+            let created = client
                 .simple_query(&format!(
-                    "SELECT pg_create_logical_replication_slot('{}', 'pgoutput', {})",
-                    replication_slot, temporary
+                    "SELECT slot_name, lsn FROM pg_create_logical_replication_slot('{}', 'pgoutput', {}, {})",
+                    replication_slot, temporary, two_phase
                 ))
-                .await;
+                .await?;
 
-            if let Err(e) = create_result {
-                return Err(Box::new(e) as Box<dyn Error>);
+            for message in created {
+                if let SimpleQueryMessage::Row(row) = message {
+                    let slot_name: String = row.get(0).ok_or("slot_name is NULL")?.to_string();
+                    let lsn = parse_lsn(row.get(1).ok_or("lsn is NULL")?)?;
+                    return Ok((slot_name, lsn, None));
+                }
             }
 
-            // Newly created replication slot always starts at 0/0
-            return Ok((replication_slot.to_string(), PgLsn::from(0)));
+            return Err("Failed to create replication slot".into());
         }
 
-        let rows = client
+        let row = rows
+            .first()
+            .ok_or("Failed to fetch replication slot info")?;
+        let slot_name: String = row.get(0).ok_or("slot_name is NULL")?.to_string();
+        let confirmed_flush_lsn = parse_lsn(row.get(1).ok_or("confirmed_flush_lsn is NULL")?)?;
+        Ok((slot_name, confirmed_flush_lsn, None))
+    }
+
+    /// Create the slot through the replication protocol, which exports a snapshot of the
+    /// database as of the slot's consistent point. The snapshot stays valid until the next
+    /// command is executed on the replication connection.
+    async fn create_replication_slot_with_snapshot(
+        &self,
+        client: &Client,
+        replication_slot: &str,
+        temporary: bool,
+        two_phase: bool,
+    ) -> Result<(String, PgLsn, Option<String>), Box<dyn Error>> {
+        let messages = client
             .simple_query(&format!(
-                "SELECT slot_name, restart_lsn FROM pg_replication_slots WHERE slot_name = '{}'",
-                replication_slot
+                "CREATE_REPLICATION_SLOT {} {} LOGICAL pgoutput EXPORT_SNAPSHOT{}",
+                replication_slot,
+                if temporary { "TEMPORARY" } else { "" },
+                if two_phase { " TWO_PHASE" } else { "" }
             ))
             .await?;
 
-        for message in rows {
+        for message in messages {
             if let SimpleQueryMessage::Row(row) = message {
-                let slot_name: String = row.get(0).ok_or("slot_name is NULL")?.to_string();
-                let restart_lsn_str: &str = row.get(1).ok_or("restart_lsn is NULL")?;
-                let restart_lsn = restart_lsn_str.parse::<PgLsn>().unwrap();
-                return Ok((slot_name, restart_lsn));
+                let slot_name = row.get(0).ok_or("slot_name is NULL")?.to_string();
+                let consistent_point = parse_lsn(row.get(1).ok_or("consistent_point is NULL")?)?;
+                let snapshot_name = row.get(2).ok_or("snapshot_name is NULL")?.to_string();
+
+                info!(
+                    "Created replication slot '{}' at {} with snapshot {}",
+                    slot_name, consistent_point, snapshot_name
+                );
+                return Ok((slot_name, consistent_point, Some(snapshot_name)));
             }
         }
 
-        Err("Failed to fetch replication slot info".into())
+        Err("CREATE_REPLICATION_SLOT returned no result".into())
     }
 
     pub async fn list_replication_slots(self) -> Result<(), Box<dyn std::error::Error>> {
@@ -181,17 +330,33 @@ impl ReplicationInstance {
         Ok(())
     }
 
-    pub async fn start(mut self, publication: &str, replication_slot: &str, temporary: bool) {
+    pub async fn start(
+        mut self,
+        publication: &str,
+        replication_slot: &str,
+        temporary: bool,
+        options: ReplicationOptions,
+    ) {
+        // Last LSN acknowledged by clients, the next cycle resumes after it
+        let mut resume_lsn = PgLsn::from(0);
         loop {
             match self
-                .stream_once(publication, replication_slot, temporary)
+                .stream_once(
+                    publication,
+                    replication_slot,
+                    temporary,
+                    resume_lsn,
+                    &options,
+                )
                 .await
             {
                 Ok(new_lsn) => {
-                    info!("Stream cycle ended, restart_lsn = {}", new_lsn);
+                    info!("Stream cycle ended, resume_lsn = {}", new_lsn);
+                    resume_lsn = new_lsn;
                 }
                 Err(e) => {
                     error!("Replication error: {}", e);
+                    resume_lsn = resume_lsn.max(*self.event_stream.last_ack_lsn.borrow());
                 }
             }
             tokio::time::sleep(Duration::from_secs(2)).await;
@@ -203,6 +368,8 @@ impl ReplicationInstance {
         publication: &str,
         replication_slot: &str,
         temporary: bool,
+        resume_lsn: PgLsn,
+        options: &ReplicationOptions,
     ) -> Result<PgLsn, Box<dyn std::error::Error>> {
         let (client, connection) = tokio_postgres::connect(
             &format!("{}?replication=database", self.connection_string),
@@ -215,42 +382,141 @@ impl ReplicationInstance {
             }
         });
 
-        let (_, start_lsn) = self
-            .ensure_replication_slot(&client, replication_slot, temporary)
+        let (_, slot_lsn, exported_snapshot) = self
+            .ensure_replication_slot(
+                &client,
+                replication_slot,
+                temporary,
+                options.two_phase,
+                options.snapshot,
+            )
             .await?;
 
+        // The slot may lag behind acknowledgements that were not reported to the server yet.
+        // A new temporary slot starts after all earlier acknowledgements anyway.
+        let mut start_lsn = slot_lsn.max(resume_lsn);
+
+        // Events up to the last transaction boundary in the log are not needed again
+        if let Some(log) = self.event_stream.log() {
+            start_lsn = start_lsn.max(log.recover()?);
+        }
+        self.event_stream.last_ack_lsn.send_if_modified(|lsn| {
+            let behind = *lsn < start_lsn;
+            if behind {
+                *lsn = start_lsn;
+            }
+            behind
+        });
+        self.event_stream.resume_lsn.send_replace(start_lsn);
+
+        // Regular connection for catalog lookups while the replication connection is streaming
+        let (catalog_client, catalog_connection) =
+            tokio_postgres::connect(&self.connection_string, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = catalog_connection.await {
+                error!("Catalog connection error: {}", e);
+            }
+        });
+
+        // Resume an incremental snapshot interrupted by a restart from its last chunk
+        self.signal_table = options.signal_table.clone();
+        self.lossless = options.lossless;
+        self.binary = options.binary;
+        self.pending.lock().unwrap().clear();
+        self.snapshot_window = None;
+        self.incremental_snapshot = match &self.signal_table {
+            Some(signal_table) => signal_table.load_progress(&catalog_client).await?,
+            None => None,
+        };
+
+        // The log is written whether clients are connected or not
         info!("Waiting clients before starting replication stream...");
-        while self.event_stream.tx.receiver_count() == 0 {
+        while self.event_stream.log().is_none() && self.event_stream.tx.receiver_count() == 0 {
             tokio::time::sleep(Duration::from_millis(100)).await;
         }
-        info!("Starting replication stream...");
+
+        if let Some(snapshot_name) = exported_snapshot
+            && let Err(e) = self
+                .send_snapshot(&catalog_client, publication, &snapshot_name, start_lsn)
+                .await
+        {
+            // A permanent slot would resume after the snapshot, drop it to take a new one next time
+            if !temporary {
+                client
+                    .simple_query(&format!("DROP_REPLICATION_SLOT {}", replication_slot))
+                    .await?;
+            }
+            return Err(e);
+        }
+
+        info!("Starting replication stream from {}...", start_lsn);
 
         let replication_query = format!(
-            "START_REPLICATION SLOT {} LOGICAL {} (proto_version '1', publication_names '{}')",
-            replication_slot, start_lsn, publication
+            "START_REPLICATION SLOT {} LOGICAL {} ({})",
+            replication_slot,
+            start_lsn,
+            options.plugin_options(publication)
         );
 
         let copy_stream = client
             .copy_both_simple::<bytes::Bytes>(&replication_query)
             .await?;
 
-        let stream = LogicalReplicationStream::new(copy_stream);
+        let stream = ReplicationStream::new(copy_stream);
         tokio::pin!(stream);
 
-        let mut relation_store: std::collections::HashMap<u32, RelationInfo> =
-            std::collections::HashMap::new();
+        if self.incremental_snapshot.is_some() {
+            info!("Resuming incremental snapshot");
+            self.next_snapshot_chunk(&catalog_client).await?;
+        }
+
+        let mut decoder = PgOutputDecoder::default();
+
+        // Transaction currently being decoded: (xid, number of row events sent)
+        let mut transaction: Option<(u32, u64)> = None;
 
-        let last_received_lsn = self.event_stream.last_received_lsn.subscribe();
-        let last_sent_lsn = self.event_stream.last_sent_lsn.subscribe();
-        let last_ack_lsn = self.event_stream.last_ack_lsn.subscribe();
+        // Changes of streamed in-progress transactions, held back until they commit
+        let mut streamed: HashMap<u32, TransactionBuffer<StreamedChange>> = HashMap::new();
+        let mut streaming_xid: Option<u32> = None;
+
+        // Snapshots requested through gRPC, only accepted with a signal table
+        let mut snapshot_requests = self
+            .signal_table
+            .as_ref()
+            .map(|_| self.event_stream.snapshot_requests.subscribe());
+
+        // Consumers reconnecting behind the stream, a temporary slot cannot replay for them.
+        // With the event log they read the events they missed from it instead.
+        let mut replay_lsn = (!temporary && self.event_stream.log().is_none())
+            .then(|| self.event_stream.replay_lsn.subscribe());
 
         info!("Connected to replication slot '{}'", replication_slot);
-        while let Some(msg) = stream.next().await {
-            let msg = msg?;
+        loop {
+            // In lossless mode, stop reading while the slowest client is too far behind
+            if self.lossless {
+                self.wait_for_clients(stream.as_mut()).await;
+            }
+
+            let msg = tokio::select! {
+                msg = stream.next() => match msg {
+                    Some(msg) => msg?,
+                    None => break,
+                },
+                Some(request) = recv_snapshot_request(&mut snapshot_requests) => {
+                    self.request_snapshot(&request, &catalog_client).await?;
+                    continue;
+                }
+                Some(cursor) = recv_replay_lsn(&mut replay_lsn) => {
+                    info!("Consumer reconnected at {}, restarting replication to replay", cursor);
+                    break;
+                }
+            };
 
             match msg {
                 ReplicationMessage::XLogData(xlog) => {
-                    if self.event_stream.tx.receiver_count() == 0 {
+                    if self.event_stream.log().is_none()
+                        && self.event_stream.tx.receiver_count() == 0
+                    {
                         info!(
                             "No clients connected, pause replication steam until next connection"
                         );
@@ -261,222 +527,1195 @@ impl ReplicationInstance {
 
                     self.event_stream
                         .last_received_lsn
-                        .send(PgLsn::from(xlog.wal_end()))
-                        .ok();
-
-                    match xlog.data() {
-                        LogicalReplicationMessage::Relation(body) => {
-                            debug!("Relation {:#?}", body);
-
-                            relation_store.insert(
-                                body.rel_id(),
-                                RelationInfo {
-                                    schema: body.namespace()?.into(),
-                                    table: body.name()?.into(),
-                                    columns: body
-                                        .columns()
-                                        .iter()
-                                        .map(|c| c.name().unwrap().to_string())
-                                        .collect::<Vec<String>>(),
-                                },
-                            );
-                        }
-                        LogicalReplicationMessage::Insert(body) => {
-                            let relation = relation_store.get(&body.rel_id()).unwrap();
-                            let json_payload = tuple_to_json(&relation.columns, body.tuple());
+                        .send_replace(PgLsn::from(xlog.wal_end()));
 
-                            debug!("Insert {:#?} ", body);
+                    match decoder.decode(xlog.data())? {
+                        PgOutputMessage::Logical(message) => {
+                            self.handle_message(
+                                xlog.wal_end(),
+                                &message,
+                                &mut transaction,
+                                &catalog_client,
+                            )
+                            .await?;
+                        }
+                        PgOutputMessage::Message(body) => {
+                            self.publish_logical_message(&body, &transaction)?;
+                        }
+                        PgOutputMessage::StreamStart { xid, first_segment } => {
+                            debug!("Stream start xid={} first_segment={}", xid, first_segment);
+                            streaming_xid = Some(xid);
+                        }
+                        PgOutputMessage::StreamStop => {
+                            debug!("Stream stop");
+                            streaming_xid = None;
+                        }
+                        PgOutputMessage::Streamed { subxid, data } => {
+                            let xid = streaming_xid.ok_or("Streamed change outside of a stream")?;
+                            streamed
+                                .entry(xid)
+                                .or_insert_with(|| {
+                                    TransactionBuffer::new(options.transaction_memory_limit)
+                                })
+                                .push(StreamedChange {
+                                    lsn: xlog.wal_end(),
+                                    subxid,
+                                    data,
+                                })?;
+                        }
+                        PgOutputMessage::StreamAbort { xid, subxid } => {
+                            debug!("Stream abort xid={} subxid={}", xid, subxid);
 
-                            match self.event_stream.tx.send(ServerMessage {
-                                msg: Some(plugin::server_message::Msg::Insert(InsertEvent {
-                                    pg_lsn: xlog.wal_end(),
-                                    schema: relation.schema.clone(),
-                                    table: relation.table.clone(),
-                                    json_payload: json_payload.to_string(),
-                                })),
-                            }) {
-                                Ok(n) => {
-                                    self.event_stream
-                                        .last_sent_lsn
-                                        .send(PgLsn::from(xlog.wal_end()))
-                                        .ok();
-                                    debug!("Sent event to {} subscribers", n);
-                                }
-                                Err(_) => (),
+                            if xid == subxid {
+                                streamed.remove(&xid);
+                            } else if let Some(buffer) = streamed.get_mut(&xid) {
+                                buffer.retain(|change| change.subxid != subxid)?;
                             }
                         }
-                        LogicalReplicationMessage::Update(body) => {
-                            let relation = relation_store.get(&body.rel_id()).unwrap();
-                            let json_payload = tuple_to_json(&relation.columns, body.new_tuple());
+                        PgOutputMessage::StreamCommit {
+                            xid,
+                            commit_lsn,
+                            end_lsn,
+                            timestamp,
+                        } => {
+                            debug!("Stream commit xid={}", xid);
 
-                            debug!("Update {:#?}", body);
+                            let mut changes = match streamed.remove(&xid) {
+                                Some(mut buffer) => buffer.drain()?,
+                                None => Drain::default(),
+                            }
+                            .peekable();
+
+                            // Release the streamed changes as a regular transaction
+                            transaction = Some((xid, 0));
+                            self.publish(
+                                xlog.wal_end(),
+                                plugin::server_message::Msg::Begin(BeginEvent {
+                                    pg_lsn: changes
+                                        .peek()
+                                        .and_then(|change| change.as_ref().ok())
+                                        .map(|change| change.lsn)
+                                        .unwrap_or(xlog.wal_end()),
+                                    xid,
+                                    final_lsn: commit_lsn,
+                                    commit_timestamp: to_unix_micros(timestamp),
+                                }),
+                            )?;
 
-                            match self.event_stream.tx.send(ServerMessage {
-                                msg: Some(plugin::server_message::Msg::Update(UpdateEvent {
+                            self.replay_streamed(
+                                stream.as_mut(),
+                                changes,
+                                &mut transaction,
+                                &catalog_client,
+                            )
+                            .await?;
+
+                            let (xid, row_count) = transaction.take().unwrap_or_default();
+                            self.publish(
+                                xlog.wal_end(),
+                                plugin::server_message::Msg::Commit(CommitEvent {
                                     pg_lsn: xlog.wal_end(),
-                                    schema: relation.schema.clone(),
-                                    table: relation.table.clone(),
-                                    json_payload: json_payload.to_string(),
-                                })),
-                            }) {
-                                Ok(n) => {
-                                    self.event_stream
-                                        .last_sent_lsn
-                                        .send(PgLsn::from(xlog.wal_end()))
-                                        .ok();
-                                    debug!("Sent event to {} subscribers", n);
-                                }
-                                Err(_) => (),
-                            }
+                                    xid,
+                                    commit_lsn,
+                                    end_lsn,
+                                    commit_timestamp: to_unix_micros(timestamp),
+                                    row_count,
+                                }),
+                            )?;
                         }
-                        LogicalReplicationMessage::Delete(body) => {
-                            let relation = relation_store.get(&body.rel_id()).unwrap();
-                            let json_payload =
-                                tuple_to_json(&relation.columns, body.key_tuple().unwrap());
+                        PgOutputMessage::BeginPrepare(body) => {
+                            debug!("Begin prepare {:#?}", body);
 
-                            debug!("Delete {} {:#?}", relation.table, body);
+                            transaction = Some((body.xid, 0));
+                            self.publish(
+                                xlog.wal_end(),
+                                plugin::server_message::Msg::BeginPrepare(begin_prepare_event(
+                                    xlog.wal_end(),
+                                    &body,
+                                )),
+                            )?;
+                        }
+                        PgOutputMessage::Prepare(body) => {
+                            debug!("Prepare {:#?}", body);
 
-                            match self.event_stream.tx.send(ServerMessage {
-                                msg: Some(plugin::server_message::Msg::Delete(DeleteEvent {
-                                    pg_lsn: xlog.wal_end(),
-                                    schema: relation.schema.clone(),
-                                    table: relation.table.clone(),
-                                    json_payload: json_payload.to_string(),
-                                })),
-                            }) {
-                                Ok(n) => {
-                                    self.event_stream
-                                        .last_sent_lsn
-                                        .send(PgLsn::from(xlog.wal_end()))
-                                        .ok();
-                                    debug!("Sent event to {} subscribers", n);
-                                }
-                                Err(_) => (),
-                            }
+                            let (_, row_count) = transaction.take().unwrap_or_default();
+                            self.publish(
+                                xlog.wal_end(),
+                                plugin::server_message::Msg::Prepare(prepare_event(
+                                    xlog.wal_end(),
+                                    &body,
+                                    row_count,
+                                )),
+                            )?;
                         }
-                        LogicalReplicationMessage::Truncate(truncate) => {
-                            let relations = truncate
-                                .rel_ids()
-                                .iter()
-                                .map(|id| relation_store.get(id).unwrap())
-                                .collect::<Vec<&RelationInfo>>();
-
-                            debug!("Truncate {:#?}", truncate);
-
-                            for relation in relations.iter() {
-                                match self.event_stream.tx.send(ServerMessage {
-                                    msg: Some(plugin::server_message::Msg::Truncate(
-                                        TruncateEvent {
-                                            pg_lsn: xlog.wal_end(),
-                                            schema: relation.schema.clone(),
-                                            table: relation.table.clone(),
-                                        },
-                                    )),
-                                }) {
-                                    Ok(n) => {
-                                        self.event_stream
-                                            .last_sent_lsn
-                                            .send(PgLsn::from(xlog.wal_end()))
-                                            .ok();
-                                        debug!("Sent event to {} subscribers", n);
-                                    }
-                                    Err(_) => (),
-                                }
+                        PgOutputMessage::StreamPrepare(body) => {
+                            debug!("Stream prepare {:#?}", body);
+
+                            let mut changes = match streamed.remove(&body.xid) {
+                                Some(mut buffer) => buffer.drain()?,
+                                None => Drain::default(),
                             }
-                        }
-                        _ => debug!("Unsupported replication message body"),
-                    }
-                }
-                ReplicationMessage::PrimaryKeepAlive(_message) => {
-                    let last_received_lsn_val = *last_received_lsn.borrow(); // = last WAL received
-                    let last_sent_lsn_val = *last_sent_lsn.borrow(); // = last WAL sent to gRPC
-                    let last_ack_lsn_val = *last_ack_lsn.borrow(); // = last WAL the gRPC client acked
+                            .peekable();
 
-                    let now = SystemTime::now();
-                    let elapsed = now
-                        .duration_since(self.last_status_update)
-                        .unwrap_or_default();
+                            // Release the streamed changes as a regular prepared transaction
+                            transaction = Some((body.xid, 0));
+                            self.publish(
+                                xlog.wal_end(),
+                                plugin::server_message::Msg::BeginPrepare(begin_prepare_event(
+                                    changes
+                                        .peek()
+                                        .and_then(|change| change.as_ref().ok())
+                                        .map(|change| change.lsn)
+                                        .unwrap_or(xlog.wal_end()),
+                                    &body,
+                                )),
+                            )?;
 
-                    if self.last_ack_lsn_sent != last_ack_lsn_val
-                        || elapsed > Duration::from_secs(10)
-                    {
-                        let ts: i64 = POSTGRES_EPOCH
-                            .elapsed()
-                            .map_err(|e: std::time::SystemTimeError| {
-                                error!("Invalid PostgreSQL epoch {}", e.to_string());
-                                Box::new(e) as Box<dyn Error> // Wrap error in Box
-                            })
-                            .expect("msg")
-                            .as_micros() as i64;
-
-                        let _ = stream
-                            .as_mut()
-                            .standby_status_update(
-                                last_received_lsn_val,
-                                last_sent_lsn_val,
-                                last_ack_lsn_val,
-                                ts,
-                                0,
+                            self.replay_streamed(
+                                stream.as_mut(),
+                                changes,
+                                &mut transaction,
+                                &catalog_client,
                             )
-                            .await;
+                            .await?;
 
-                        self.last_ack_lsn_sent = last_ack_lsn_val;
-                        self.last_status_update = now;
+                            let (_, row_count) = transaction.take().unwrap_or_default();
+                            self.publish(
+                                xlog.wal_end(),
+                                plugin::server_message::Msg::Prepare(prepare_event(
+                                    xlog.wal_end(),
+                                    &body,
+                                    row_count,
+                                )),
+                            )?;
+                        }
+                        PgOutputMessage::CommitPrepared {
+                            xid,
+                            gid,
+                            commit_lsn,
+                            end_lsn,
+                            timestamp,
+                        } => {
+                            debug!("Commit prepared xid={} gid={}", xid, gid);
 
-                        debug!(
-                            "Sent standby_status_update, last_received_lsn:{} last_sent_lsn:{} last_ack_lsn:{}",
-                            last_received_lsn_val, last_sent_lsn_val, last_ack_lsn_val
-                        );
+                            self.publish(
+                                xlog.wal_end(),
+                                plugin::server_message::Msg::CommitPrepared(CommitPreparedEvent {
+                                    pg_lsn: xlog.wal_end(),
+                                    xid,
+                                    gid,
+                                    commit_lsn,
+                                    end_lsn,
+                                    commit_timestamp: to_unix_micros(timestamp),
+                                }),
+                            )?;
+                        }
+                        PgOutputMessage::RollbackPrepared {
+                            xid,
+                            gid,
+                            prepare_end_lsn,
+                            rollback_end_lsn,
+                            prepare_timestamp,
+                            rollback_timestamp,
+                        } => {
+                            debug!("Rollback prepared xid={} gid={}", xid, gid);
+
+                            self.publish(
+                                xlog.wal_end(),
+                                plugin::server_message::Msg::RollbackPrepared(
+                                    RollbackPreparedEvent {
+                                        pg_lsn: xlog.wal_end(),
+                                        xid,
+                                        gid,
+                                        prepare_end_lsn,
+                                        rollback_end_lsn,
+                                        prepare_timestamp: to_unix_micros(prepare_timestamp),
+                                        rollback_timestamp: to_unix_micros(rollback_timestamp),
+                                    },
+                                ),
+                            )?;
+                        }
+                    }
+                }
+                ReplicationMessage::PrimaryKeepAlive(_message) => {
+                    // Events durably in the log no longer need the WAL on the server
+                    if let Some(log) = self.event_stream.log() {
+                        let synced_lsn = log.sync()?;
+                        self.event_stream.last_ack_lsn.send_if_modified(|lsn| {
+                            let advanced = *lsn < synced_lsn;
+                            if advanced {
+                                *lsn = synced_lsn;
+                            }
+                            advanced
+                        });
                     }
+
+                    self.send_status_update(stream.as_mut()).await;
                 }
                 _ => {}
             }
         }
 
-        let start_lsn = *last_ack_lsn.borrow();
+        let start_lsn = *self.event_stream.last_ack_lsn.borrow();
         Ok(start_lsn) // Re-start replication from the last processec event
     }
-}
 
-fn tuple_to_json(columns: &[String], tuple: &Tuple) -> Value {
-    let mut obj = serde_json::Map::new();
+    /// Report the replication progress to the server when the acknowledged LSN changed, and at
+    /// least every 10 seconds
+    async fn send_status_update(&mut self, stream: Pin<&mut ReplicationStream>) {
+        let last_received_lsn_val = *self.event_stream.last_received_lsn.borrow();
+        let last_sent_lsn_val = *self.event_stream.last_sent_lsn.borrow();
+        let last_ack_lsn_val = *self.event_stream.last_ack_lsn.borrow();
 
-    for (col, field) in columns.iter().zip(tuple.tuple_data().iter()) {
-        let value = match field {
-            TupleData::Null => Value::Null,
-            TupleData::Text(bytes) => {
-                let s = String::from_utf8_lossy(bytes).to_string();
-                parse_json_value(&s)
+        let now = SystemTime::now();
+        let elapsed = now
+            .duration_since(self.last_status_update)
+            .unwrap_or_default();
+
+        if self.last_ack_lsn_sent != last_ack_lsn_val || elapsed > Duration::from_secs(10) {
+            let ts: i64 = POSTGRES_EPOCH
+                .elapsed()
+                .map_err(|e: std::time::SystemTimeError| {
+                    error!("Invalid PostgreSQL epoch {}", e.to_string());
+                    Box::new(e) as Box<dyn Error> // Wrap error in Box
+                })
+                .expect("msg")
+                .as_micros() as i64;
+
+            let _ = stream
+                .standby_status_update(
+                    last_received_lsn_val,
+                    last_sent_lsn_val,
+                    last_ack_lsn_val,
+                    ts,
+                    0,
+                )
+                .await;
+
+            self.last_ack_lsn_sent = last_ack_lsn_val;
+            self.last_status_update = now;
+
+            debug!(
+                "Sent standby_status_update, last_received_lsn:{} last_sent_lsn:{} last_ack_lsn:{}",
+                last_received_lsn_val, last_sent_lsn_val, last_ack_lsn_val
+            );
+        }
+    }
+
+    /// Lossless mode: deliver the held back events and wait until the slowest client has room
+    /// for more, keeping the walsender from timing out with status updates meanwhile
+    async fn wait_for_clients(&mut self, mut stream: Pin<&mut ReplicationStream>) {
+        let mut paused = false;
+        loop {
+            self.flush_pending();
+            if self.event_stream.tx.receiver_count() == 0 {
+                // Nobody acknowledged the held back events, they are streamed again later
+                self.pending.lock().unwrap().clear();
+                return;
+            }
+            if self.pending.lock().unwrap().is_empty()
+                && self.event_stream.tx.len() < LOSSLESS_RESUME_LEN
+            {
+                if paused {
+                    debug!("Clients caught up, resuming replication stream");
+                }
+                return;
+            }
+
+            if !paused {
+                debug!("Clients are behind, pausing replication stream");
+                paused = true;
             }
-            TupleData::Binary(bytes) => {
-                serde_json::Value::String(String::from_utf8_lossy(bytes).to_string())
+            self.send_status_update(stream.as_mut()).await;
+            // Forwarders signal every event they receive, status updates continue meanwhile
+            let _ = tokio::time::timeout(
+                Duration::from_secs(1),
+                self.event_stream.received.notified(),
+            )
+            .await;
+        }
+    }
+
+    /// Release held back events while the broadcast channel has room for them
+    fn flush_pending(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        while self.event_stream.tx.len() < EVENT_BUFFER_CAPACITY
+            && let Some((pg_lsn, msg)) = pending.pop_front()
+        {
+            self.broadcast(pg_lsn, msg);
+        }
+    }
+
+    /// Release the held back changes of a streamed transaction through `handle_message`
+    async fn replay_streamed(
+        &mut self,
+        mut stream: Pin<&mut ReplicationStream>,
+        changes: impl Iterator<Item = std::io::Result<StreamedChange>>,
+        transaction: &mut Option<(u32, u64)>,
+        catalog_client: &Client,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Spilled changes are read back one at a time, not all at once
+        for change in changes {
+            // A streamed transaction can be larger than memory, do not queue it up for clients
+            if self.lossless {
+                self.wait_for_clients(stream.as_mut()).await;
             }
-            TupleData::UnchangedToast => Value::Null,
+            let change = change?;
+            match pgoutput::parse_streamed(&change.data)? {
+                PgOutputMessage::Logical(message) => {
+                    self.handle_message(change.lsn, &message, transaction, catalog_client)
+                        .await?;
+                }
+                PgOutputMessage::Message(body) => {
+                    self.publish_logical_message(&body, transaction)?;
+                }
+                message => debug!("Unexpected message in streamed transaction {:?}", message),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn publish_logical_message(
+        &self,
+        body: &MessageBody,
+        transaction: &Option<(u32, u64)>,
+    ) -> std::io::Result<()> {
+        debug!("Message {:#?}", body);
+
+        // Non-transactional messages are sent immediately, outside of any transaction
+        let xid = match transaction {
+            Some((xid, _)) if body.transactional => *xid,
+            _ => 0,
         };
 
-        obj.insert(col.clone(), value);
+        self.publish(
+            body.lsn,
+            plugin::server_message::Msg::LogicalMessage(LogicalMessageEvent {
+                pg_lsn: body.lsn,
+                xid,
+                transactional: body.transactional,
+                prefix: body.prefix.clone(),
+                content: body.content.to_vec(),
+            }),
+        )
     }
 
-    Value::Object(obj)
+    /// Translate a decoded pgoutput message into events for the clients
+    async fn handle_message(
+        &mut self,
+        wal_end: u64,
+        message: &LogicalReplicationMessage,
+        transaction: &mut Option<(u32, u64)>,
+        catalog_client: &Client,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match message {
+            LogicalReplicationMessage::Begin(body) => {
+                debug!("Begin {:#?}", body);
+
+                *transaction = Some((body.xid(), 0));
+
+                self.publish(
+                    wal_end,
+                    plugin::server_message::Msg::Begin(BeginEvent {
+                        pg_lsn: wal_end,
+                        xid: body.xid(),
+                        final_lsn: body.final_lsn(),
+                        commit_timestamp: to_unix_micros(body.timestamp()),
+                    }),
+                )?;
+            }
+            LogicalReplicationMessage::Commit(body) => {
+                debug!("Commit {:#?}", body);
+
+                let (xid, row_count) = transaction.take().unwrap_or_default();
+
+                self.publish(
+                    wal_end,
+                    plugin::server_message::Msg::Commit(CommitEvent {
+                        pg_lsn: wal_end,
+                        xid,
+                        commit_lsn: body.commit_lsn(),
+                        end_lsn: body.end_lsn(),
+                        commit_timestamp: to_unix_micros(body.timestamp()),
+                        row_count,
+                    }),
+                )?;
+            }
+            LogicalReplicationMessage::Relation(body) => {
+                debug!("Relation {:#?}", body);
+
+                let type_oids: Vec<u32> =
+                    body.columns().iter().map(|c| c.type_id() as u32).collect();
+                let types = decode::resolve_types(catalog_client, &type_oids).await?;
+                let relation = RelationInfo {
+                    schema: body.namespace()?.into(),
+                    table: body.name()?.into(),
+                    replica_identity: match body.replica_identity() {
+                        ReplicaIdentity::Default => plugin::ReplicaIdentity::Default,
+                        ReplicaIdentity::Nothing => plugin::ReplicaIdentity::Nothing,
+                        ReplicaIdentity::Full => plugin::ReplicaIdentity::Full,
+                        ReplicaIdentity::Index => plugin::ReplicaIdentity::Index,
+                    },
+                    columns: body
+                        .columns()
+                        .iter()
+                        .map(|c| ColumnInfo {
+                            name: c.name().unwrap().to_string(),
+                            flags: c.flags(),
+                            type_oid: c.type_id() as u32,
+                            decode_oid: types
+                                .get(&(c.type_id() as u32))
+                                .copied()
+                                .unwrap_or(c.type_id() as u32),
+                            type_modifier: c.type_modifier(),
+                        })
+                        .collect::<Vec<ColumnInfo>>(),
+                };
+
+                // Relation messages repeat in every session, only announce changes
+                if self.relation_store.get(&body.rel_id()) != Some(&relation) {
+                    let columns =
+                        describe_columns(catalog_client, body.rel_id(), &relation.columns).await?;
+
+                    info!(
+                        "Schema of {}.{} changed, notifying clients",
+                        relation.schema, relation.table
+                    );
+
+                    self.publish(
+                        wal_end,
+                        plugin::server_message::Msg::Schema(SchemaEvent {
+                            pg_lsn: wal_end,
+                            relation_id: body.rel_id(),
+                            schema: relation.schema.clone(),
+                            table: relation.table.clone(),
+                            replica_identity: relation.replica_identity.into(),
+                            columns,
+                        }),
+                    )?;
+                }
+
+                self.relation_store.insert(body.rel_id(), relation);
+            }
+            LogicalReplicationMessage::Insert(body) => {
+                let relation = self.relation_store.get(&body.rel_id()).unwrap();
+                let json_payload = tuple_to_json(&relation.columns, body.tuple());
+
+                debug!("Insert {:#?} ", body);
+
+                if self.is_signal_table(relation) {
+                    return self
+                        .handle_signal(wal_end, &json_payload, catalog_client)
+                        .await;
+                }
+                snapshot_window_changed(&mut self.snapshot_window, relation, &[&json_payload]);
+
+                let xid = count_row(transaction);
+                self.publish(
+                    wal_end,
+                    plugin::server_message::Msg::Insert(InsertEvent {
+                        pg_lsn: wal_end,
+                        schema: relation.schema.clone(),
+                        table: relation.table.clone(),
+                        json_payload: json_payload.to_string(),
+                        xid,
+                    }),
+                )?;
+            }
+            LogicalReplicationMessage::Update(body) => {
+                let relation = self.relation_store.get(&body.rel_id()).unwrap();
+                let json_payload = tuple_to_json(&relation.columns, body.new_tuple());
+                let old_tuple = body.old_tuple();
+                let old_json_payload = old_tuple
+                    .map(|t| tuple_to_json(&relation.columns, t).to_string())
+                    .unwrap_or_default();
+                // Old key is only sent when it changed, otherwise it is in the new row
+                let key_json_payload = key_to_json(
+                    &relation.columns,
+                    body.key_tuple().or(old_tuple).unwrap_or(body.new_tuple()),
+                );
+
+                debug!("Update {:#?}", body);
+
+                if self.is_signal_table(relation) {
+                    return Ok(());
+                }
+                snapshot_window_changed(
+                    &mut self.snapshot_window,
+                    relation,
+                    &[&json_payload, &key_json_payload],
+                );
+
+                let xid = count_row(transaction);
+                self.publish(
+                    wal_end,
+                    plugin::server_message::Msg::Update(UpdateEvent {
+                        pg_lsn: wal_end,
+                        schema: relation.schema.clone(),
+                        table: relation.table.clone(),
+                        json_payload: json_payload.to_string(),
+                        xid,
+                        old_json_payload,
+                        key_json_payload: key_json_payload.to_string(),
+                        unchanged_columns: unchanged_columns(&relation.columns, body.new_tuple()),
+                    }),
+                )?;
+            }
+            LogicalReplicationMessage::Delete(body) => {
+                let relation = self.relation_store.get(&body.rel_id()).unwrap();
+                // REPLICA IDENTITY FULL sends the old row instead of the key
+                let (json_payload, old_json_payload, key_json_payload) =
+                    match (body.old_tuple(), body.key_tuple()) {
+                        (Some(old), _) => {
+                            let old_json = tuple_to_json(&relation.columns, old);
+                            let key_json = key_to_json(&relation.columns, old);
+                            (old_json.clone(), old_json.to_string(), key_json)
+                        }
+                        (None, Some(key)) => {
+                            let key_json = key_to_json(&relation.columns, key);
+                            (key_json.clone(), String::new(), key_json)
+                        }
+                        (None, None) => (
+                            Value::Object(serde_json::Map::new()),
+                            String::new(),
+                            Value::Object(serde_json::Map::new()),
+                        ),
+                    };
+
+                debug!("Delete {} {:#?}", relation.table, body);
+
+                if self.is_signal_table(relation) {
+                    return Ok(());
+                }
+                snapshot_window_changed(&mut self.snapshot_window, relation, &[&key_json_payload]);
+
+                let xid = count_row(transaction);
+                self.publish(
+                    wal_end,
+                    plugin::server_message::Msg::Delete(DeleteEvent {
+                        pg_lsn: wal_end,
+                        schema: relation.schema.clone(),
+                        table: relation.table.clone(),
+                        json_payload: json_payload.to_string(),
+                        xid,
+                        old_json_payload,
+                        key_json_payload: key_json_payload.to_string(),
+                    }),
+                )?;
+            }
+            LogicalReplicationMessage::Truncate(truncate) => {
+                let relations = truncate
+                    .rel_ids()
+                    .iter()
+                    .map(|id| self.relation_store.get(id).unwrap())
+                    .filter(|relation| !self.is_signal_table(relation))
+                    .collect::<Vec<&RelationInfo>>();
+
+                debug!("Truncate {:#?}", truncate);
+
+                for relation in relations.iter() {
+                    let xid = count_row(transaction);
+                    self.publish(
+                        wal_end,
+                        plugin::server_message::Msg::Truncate(TruncateEvent {
+                            pg_lsn: wal_end,
+                            schema: relation.schema.clone(),
+                            table: relation.table.clone(),
+                            xid,
+                        }),
+                    )?;
+                }
+            }
+            _ => debug!("Unsupported replication message body"),
+        }
+
+        Ok(())
+    }
+
+    /// Send the rows of every published table as seen by the exported snapshot
+    async fn send_snapshot(
+        &self,
+        client: &Client,
+        publication: &str,
+        snapshot_name: &str,
+        consistent_point: PgLsn,
+    ) -> Result<(), Box<dyn Error>> {
+        let pg_lsn = u64::from(consistent_point);
+
+        client
+            .batch_execute(&format!(
+                "BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY; SET TRANSACTION SNAPSHOT '{}'",
+                snapshot_name
+            ))
+            .await?;
+
+        let mut tables = snapshot::publication_tables(client, publication).await?;
+        if let Some(signal_table) = &self.signal_table {
+            tables.retain(|table| table != &signal_table.table);
+        }
+        info!("Sending snapshot of {} tables", tables.len());
+
+        let mut total_rows = 0;
+        for table in &tables {
+            self.publish_snapshot_progress(pg_lsn, SnapshotStage::TableStarted, table, 0)?;
+
+            let mut row_count = 0;
+            let mut cursor = TableCursor::open(client, table, self.binary).await?;
+            while let Some(rows) = cursor.fetch().await? {
+                // Snapshots are large, wait for clients to catch up instead of lagging them
+                while self.event_stream.tx.len() > snapshot::FETCH_SIZE {
+                    let _ = tokio::time::timeout(
+                        Duration::from_secs(1),
+                        self.event_stream.received.notified(),
+                    )
+                    .await;
+                }
+
+                row_count += rows.len() as u64;
+                for row in rows {
+                    self.publish(
+                        pg_lsn,
+                        plugin::server_message::Msg::Snapshot(SnapshotEvent {
+                            pg_lsn,
+                            schema: table.schema.clone(),
+                            table: table.table.clone(),
+                            json_payload: row.to_string(),
+                        }),
+                    )?;
+                }
+            }
+
+            debug!(
+                "Snapshot of {}.{} sent {} rows",
+                table.schema, table.table, row_count
+            );
+            self.publish_snapshot_progress(
+                pg_lsn,
+                SnapshotStage::TableCompleted,
+                table,
+                row_count,
+            )?;
+            total_rows += row_count;
+        }
+
+        client.batch_execute("COMMIT").await?;
+
+        self.publish(
+            pg_lsn,
+            plugin::server_message::Msg::SnapshotProgress(SnapshotProgressEvent {
+                pg_lsn,
+                stage: SnapshotStage::SnapshotCompleted.into(),
+                row_count: total_rows,
+                ..Default::default()
+            }),
+        )?;
+        info!("Snapshot completed, sent {} rows", total_rows);
+
+        Ok(())
+    }
+
+    fn is_signal_table(&self, relation: &RelationInfo) -> bool {
+        self.signal_table.as_ref().is_some_and(|signal_table| {
+            signal_table
+                .table
+                .matches(&relation.schema, &relation.table)
+        })
+    }
+
+    /// Act on a row inserted into the signal table, signal rows are not sent to clients
+    async fn handle_signal(
+        &mut self,
+        wal_end: u64,
+        row: &Value,
+        catalog_client: &Client,
+    ) -> Result<(), Box<dyn Error>> {
+        let (Some(signal_table), Some(signal)) =
+            (self.signal_table.clone(), Signal::from_json(row))
+        else {
+            return Ok(());
+        };
+
+        match signal.kind.as_str() {
+            snapshot::SIGNAL_EXECUTE_SNAPSHOT => {
+                // Signals are deleted once handled, so a signal replayed after a restart is skipped
+                if signal_table.delete(catalog_client, &signal.id).await? == 0 {
+                    debug!("Signal {} was already handled", signal.id);
+                    return Ok(());
+                }
+
+                let tables = signal.data_collections();
+                info!(
+                    "Incremental snapshot requested for {} tables by signal {}",
+                    tables.len(),
+                    signal.id
+                );
+
+                let progress = self.incremental_snapshot.get_or_insert_default();
+                for table in tables {
+                    if !progress.tables.contains(&table) {
+                        progress.tables.push_back(table);
+                    }
+                }
+                signal_table.save_progress(catalog_client, progress).await?;
+
+                if self.snapshot_window.is_none() {
+                    self.next_snapshot_chunk(catalog_client).await?;
+                }
+            }
+            snapshot::SIGNAL_WINDOW_OPEN | snapshot::SIGNAL_WINDOW_CLOSE
+                if self.snapshot_window.as_ref().is_none_or(|window| {
+                    snapshot::watermark_id(&window.id, &signal.kind) != signal.id
+                }) =>
+            {
+                // Left behind by a window that was abandoned, e.g. by a restart
+                debug!("Removing stale watermark {}", signal.id);
+                signal_table.delete(catalog_client, &signal.id).await?;
+            }
+            snapshot::SIGNAL_WINDOW_OPEN => {
+                if let Some(window) = self.snapshot_window.as_mut() {
+                    window.open = true;
+                }
+            }
+            snapshot::SIGNAL_WINDOW_CLOSE => {
+                let window = self.snapshot_window.take().unwrap();
+                signal_table
+                    .delete_watermarks(catalog_client, &window.id)
+                    .await?;
+                self.close_snapshot_window(wal_end, window, &signal_table, catalog_client)
+                    .await?;
+                self.next_snapshot_chunk(catalog_client).await?;
+            }
+            _ => debug!("Ignoring signal {} of type {}", signal.id, signal.kind),
+        }
+
+        Ok(())
+    }
+
+    /// Send the rows of a chunk that did not change while its window was open
+    async fn close_snapshot_window(
+        &mut self,
+        wal_end: u64,
+        window: SnapshotWindow,
+        signal_table: &SignalTable,
+        catalog_client: &Client,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(progress) = self.incremental_snapshot.as_mut() else {
+            return Ok(());
+        };
+
+        let table = window.table.clone();
+        let table_completed = window.rows_read() < signal_table.chunk_size;
+        progress.last_key = window.last_key().or(progress.last_key.take());
+
+        let rows = window.into_rows();
+        progress.row_count += rows.len() as u64;
+        let row_count = progress.row_count;
+
+        if table_completed {
+            progress.tables.pop_front();
+            progress.last_key = None;
+            progress.row_count = 0;
+        }
+        signal_table.save_progress(catalog_client, progress).await?;
+
+        for row in rows {
+            self.publish(
+                wal_end,
+                plugin::server_message::Msg::Snapshot(SnapshotEvent {
+                    pg_lsn: wal_end,
+                    schema: table.schema.clone(),
+                    table: table.table.clone(),
+                    json_payload: row.to_string(),
+                }),
+            )?;
+        }
+
+        if table_completed {
+            info!(
+                "Incremental snapshot of {}.{} sent {} rows",
+                table.schema, table.table, row_count
+            );
+            self.publish_snapshot_progress(
+                wal_end,
+                SnapshotStage::TableCompleted,
+                &table,
+                row_count,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Read the next chunk of the incremental snapshot between a low and a high watermark.
+    /// The rows are sent once the high watermark arrives in the stream.
+    async fn next_snapshot_chunk(&mut self, catalog_client: &Client) -> Result<(), Box<dyn Error>> {
+        let Some(signal_table) = self.signal_table.clone() else {
+            return Ok(());
+        };
+
+        while let Some(progress) = self.incremental_snapshot.as_mut() {
+            let pg_lsn = u64::from(*self.event_stream.last_received_lsn.borrow());
+
+            let Some(target) = progress.tables.front().cloned() else {
+                signal_table.clear_progress(catalog_client).await?;
+                self.incremental_snapshot = None;
+                self.publish(
+                    pg_lsn,
+                    plugin::server_message::Msg::SnapshotProgress(SnapshotProgressEvent {
+                        pg_lsn,
+                        stage: SnapshotStage::SnapshotCompleted.into(),
+                        ..Default::default()
+                    }),
+                )?;
+                info!("Incremental snapshot completed");
+                return Ok(());
+            };
+
+            let table = target.table.clone();
+            let last_key = progress.last_key.clone();
+
+            let key_columns = match snapshot::primary_key(catalog_client, &table).await {
+                Ok(key_columns) if !key_columns.is_empty() => key_columns,
+                Ok(_) => {
+                    warn!(
+                        "Skipping incremental snapshot of {}.{} without a primary key as its \
+                         replica identity",
+                        table.schema, table.table
+                    );
+                    self.skip_snapshot_table(&signal_table, catalog_client)
+                        .await?;
+                    continue;
+                }
+                Err(e) => {
+                    warn!(
+                        "Skipping incremental snapshot of {}.{}: {}",
+                        table.schema, table.table, e
+                    );
+                    self.skip_snapshot_table(&signal_table, catalog_client)
+                        .await?;
+                    continue;
+                }
+            };
+
+            if last_key.is_none() {
+                self.publish_snapshot_progress(pg_lsn, SnapshotStage::TableStarted, &table, 0)?;
+            }
+
+            let columns = snapshot::table_columns(catalog_client, &table).await?;
+            let window_id = snapshot::signal_id();
+
+            signal_table
+                .write_watermark(catalog_client, &window_id, snapshot::SIGNAL_WINDOW_OPEN)
+                .await?;
+            let chunk = snapshot::read_chunk(
+                catalog_client,
+                &target,
+                &columns,
+                &key_columns,
+                last_key.as_deref(),
+                signal_table.chunk_size,
+                self.binary,
+            )
+            .await;
+            // An invalid filter would fail on every retry, give up on the table instead
+            let rows = match chunk {
+                Ok(rows) => rows,
+                Err(e) => {
+                    warn!(
+                        "Skipping incremental snapshot of {}.{}: {}",
+                        table.schema, table.table, e
+                    );
+                    signal_table
+                        .delete_watermarks(catalog_client, &window_id)
+                        .await?;
+                    self.skip_snapshot_table(&signal_table, catalog_client)
+                        .await?;
+                    continue;
+                }
+            };
+            signal_table
+                .write_watermark(catalog_client, &window_id, snapshot::SIGNAL_WINDOW_CLOSE)
+                .await?;
+
+            let window = SnapshotWindow::new(window_id, table.clone(), key_columns, rows);
+
+            debug!(
+                "Read chunk of {} rows from {}.{} in window {}",
+                window.rows_read(),
+                table.schema,
+                table.table,
+                window.id
+            );
+            self.snapshot_window = Some(window);
+            return Ok(());
+        }
+
+        Ok(())
+    }
+
+    /// Drop the table in progress from the incremental snapshot
+    async fn skip_snapshot_table(
+        &mut self,
+        signal_table: &SignalTable,
+        catalog_client: &Client,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(progress) = self.incremental_snapshot.as_mut() {
+            progress.tables.pop_front();
+            progress.last_key = None;
+            progress.row_count = 0;
+            signal_table.save_progress(catalog_client, progress).await?;
+        }
+        Ok(())
+    }
+
+    /// Write a snapshot requested through gRPC to the signal table, it is started once the
+    /// signal arrives in the stream like any other execute-snapshot signal
+    async fn request_snapshot(
+        &self,
+        request: &RequestedSnapshot,
+        catalog_client: &Client,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(signal_table) = &self.signal_table else {
+            return Ok(());
+        };
+
+        info!(
+            "Requesting incremental snapshot of {}.{} with signal {}",
+            request.target.table.schema, request.target.table.table, request.signal_id
+        );
+        signal_table
+            .execute_snapshot(
+                catalog_client,
+                &request.signal_id,
+                std::slice::from_ref(&request.target),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    fn publish_snapshot_progress(
+        &self,
+        pg_lsn: u64,
+        stage: SnapshotStage,
+        table: &snapshot::PublishedTable,
+        row_count: u64,
+    ) -> std::io::Result<()> {
+        self.publish(
+            pg_lsn,
+            plugin::server_message::Msg::SnapshotProgress(SnapshotProgressEvent {
+                pg_lsn,
+                stage: stage.into(),
+                schema: table.schema.clone(),
+                table: table.table.clone(),
+                row_count,
+            }),
+        )
+    }
+
+    /// Broadcast an event to connected clients, or append it to the event log, and advance
+    /// `last_sent_lsn`. In lossless mode, events that would overrun the slowest client are held
+    /// back until it catches up.
+    fn publish(&self, pg_lsn: u64, msg: plugin::server_message::Msg) -> std::io::Result<()> {
+        if let Some(log) = self.event_stream.log() {
+            let event = ServerMessage {
+                msg: Some(msg),
+                ..Default::default()
+            };
+            // A failed append ends the stream, the log is recovered before it restarts
+            log.append(pg_lsn, &event)?;
+            self.event_stream
+                .last_sent_lsn
+                .send_replace(PgLsn::from(pg_lsn));
+            return Ok(());
+        }
+
+        if self.lossless {
+            let mut pending = self.pending.lock().unwrap();
+            if !pending.is_empty() || self.event_stream.tx.len() >= EVENT_BUFFER_CAPACITY {
+                pending.push_back((pg_lsn, msg));
+                return Ok(());
+            }
+        }
+        self.broadcast(pg_lsn, msg);
+        Ok(())
+    }
+
+    fn broadcast(&self, pg_lsn: u64, msg: plugin::server_message::Msg) {
+        if let Ok(n) = self.event_stream.tx.send(ServerMessage {
+            msg: Some(msg),
+            ..Default::default()
+        }) {
+            self.event_stream
+                .last_sent_lsn
+                .send_replace(PgLsn::from(pg_lsn));
+            debug!("Sent event to {} subscribers", n);
+        }
+    }
 }
 
-fn parse_json_value(s: &str) -> Value {
-    if s.eq_ignore_ascii_case("null") {
-        return Value::Null;
+async fn recv_snapshot_request(
+    receiver: &mut Option<broadcast::Receiver<RequestedSnapshot>>,
+) -> Option<RequestedSnapshot> {
+    match receiver {
+        Some(receiver) => receiver.recv().await.ok(),
+        None => std::future::pending().await,
     }
-    if s.eq_ignore_ascii_case("true") {
-        return Value::Bool(true);
+}
+
+async fn recv_replay_lsn(receiver: &mut Option<watch::Receiver<PgLsn>>) -> Option<PgLsn> {
+    match receiver {
+        Some(receiver) => receiver
+            .changed()
+            .await
+            .ok()
+            .map(|_| *receiver.borrow_and_update()),
+        None => std::future::pending().await,
     }
-    if s.eq_ignore_ascii_case("false") {
-        return Value::Bool(false);
+}
+
+fn parse_lsn(lsn: &str) -> Result<PgLsn, Box<dyn Error>> {
+    lsn.parse::<PgLsn>()
+        .map_err(|_| format!("Invalid LSN '{}'", lsn).into())
+}
+
+/// Drop rows changed in the stream from the open incremental snapshot chunk of the same table
+fn snapshot_window_changed(
+    window: &mut Option<SnapshotWindow>,
+    relation: &RelationInfo,
+    rows: &[&Value],
+) {
+    if let Some(window) = window.as_mut()
+        && window.table.matches(&relation.schema, &relation.table)
+    {
+        for row in rows {
+            window.changed(row);
+        }
     }
-    if let Ok(i) = s.parse::<i64>() {
-        return Value::Number(i.into());
+}
+
+/// Look up type names and nullability of relation columns, which pgoutput does not send
+async fn describe_columns(
+    client: &Client,
+    rel_id: u32,
+    columns: &[ColumnInfo],
+) -> Result<Vec<ColumnSchema>, tokio_postgres::Error> {
+    let names: Vec<&str> = columns.iter().map(|c| c.name.as_str()).collect();
+    let type_oids: Vec<u32> = columns.iter().map(|c| c.type_oid).collect();
+    let type_modifiers: Vec<i32> = columns.iter().map(|c| c.type_modifier).collect();
+
+    let rows = client
+        .query(
+            "SELECT format_type(c.type_oid, c.type_modifier), coalesce(a.attnotnull, false)
+             FROM unnest($1::text[], $2::oid[], $3::int4[])
+                 WITH ORDINALITY AS c(name, type_oid, type_modifier, position)
+             LEFT JOIN pg_attribute a
+                 ON a.attrelid = $4::oid AND a.attname = c.name AND NOT a.attisdropped
+             ORDER BY c.position",
+            &[&names, &type_oids, &type_modifiers, &rel_id],
+        )
+        .await?;
+
+    Ok(columns
+        .iter()
+        .zip(rows.iter())
+        .map(|(column, row)| ColumnSchema {
+            name: column.name.clone(),
+            type_oid: column.type_oid,
+            type_name: row.get(0),
+            type_modifier: column.type_modifier,
+            nullable: !row.get::<_, bool>(1),
+            key: column.is_key(),
+        })
+        .collect())
+}
+
+fn begin_prepare_event(pg_lsn: u64, body: &PrepareBody) -> BeginPrepareEvent {
+    BeginPrepareEvent {
+        pg_lsn,
+        xid: body.xid,
+        gid: body.gid.clone(),
+        prepare_lsn: body.prepare_lsn,
+        end_lsn: body.end_lsn,
+        prepare_timestamp: to_unix_micros(body.timestamp),
+    }
+}
+
+fn prepare_event(pg_lsn: u64, body: &PrepareBody, row_count: u64) -> PrepareEvent {
+    PrepareEvent {
+        pg_lsn,
+        xid: body.xid,
+        gid: body.gid.clone(),
+        prepare_lsn: body.prepare_lsn,
+        end_lsn: body.end_lsn,
+        prepare_timestamp: to_unix_micros(body.timestamp),
+        row_count,
+    }
+}
+
+/// Count a row event towards the current transaction and return its xid
+fn count_row(transaction: &mut Option<(u32, u64)>) -> u32 {
+    match transaction {
+        Some((xid, row_count)) => {
+            *row_count += 1;
+            *xid
+        }
+        None => 0,
+    }
+}
+
+/// Convert a Postgres timestamp (microseconds since 2000-01-01) to microseconds since Unix epoch
+fn to_unix_micros(pg_timestamp: i64) -> i64 {
+    pg_timestamp + (POSTGRES_EPOCH_OFFSET_SECONDS * 1_000_000) as i64
+}
+
+/// Convert a tuple to a JSON object, omitting unchanged TOAST columns whose values were not sent
+fn tuple_to_json(columns: &[ColumnInfo], tuple: &Tuple) -> Value {
+    let mut obj = serde_json::Map::new();
+
+    for (col, field) in columns.iter().zip(tuple.tuple_data().iter()) {
+        if let Some(value) = tuple_data_to_json(col, field) {
+            obj.insert(col.name.clone(), value);
+        }
     }
-    if let Ok(f) = s.parse::<f64>() {
-        if let Some(n) = serde_json::Number::from_f64(f) {
-            return Value::Number(n);
+
+    Value::Object(obj)
+}
+
+/// Like `tuple_to_json`, but only includes the replica identity columns
+fn key_to_json(columns: &[ColumnInfo], tuple: &Tuple) -> Value {
+    let mut obj = serde_json::Map::new();
+
+    for (col, field) in columns.iter().zip(tuple.tuple_data().iter()) {
+        if col.is_key()
+            && let Some(value) = tuple_data_to_json(col, field)
+        {
+            obj.insert(col.name.clone(), value);
         }
     }
-    Value::String(s.to_string())
+
+    Value::Object(obj)
+}
+
+/// Names of the TOAST columns that were left out of the tuple because they did not change
+fn unchanged_columns(columns: &[ColumnInfo], tuple: &Tuple) -> Vec<String> {
+    columns
+        .iter()
+        .zip(tuple.tuple_data().iter())
+        .filter(|(_, field)| matches!(field, TupleData::UnchangedToast))
+        .map(|(col, _)| col.name.clone())
+        .collect()
+}
+
+fn tuple_data_to_json(column: &ColumnInfo, field: &TupleData) -> Option<Value> {
+    match field {
+        TupleData::Null => Some(Value::Null),
+        TupleData::Text(bytes) => Some(decode::decode_text(
+            column.decode_oid,
+            &String::from_utf8_lossy(bytes),
+        )),
+        TupleData::Binary(bytes) => Some(decode::decode_binary(column.decode_oid, bytes)),
+        TupleData::UnchangedToast => None,
+    }
 }
@@ -0,0 +1,229 @@
+use crate::snapshot::{PublishedTable, quote_ident};
+use std::error::Error;
+use tokio_postgres::{Client, NoTls};
+use tracing::error;
+
+/// First server version with row filters and column lists in publications
+const ROW_FILTER_SERVER_VERSION: i32 = 150000;
+
+/// Table of a publication, optionally restricted to rows matching `filter` and to `columns`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublicationTable {
+    pub table: PublishedTable,
+    /// SQL condition, e.g. `status = 'paid'`
+    pub filter: Option<String>,
+    /// Published columns, all columns when empty
+    pub columns: Vec<String>,
+}
+
+impl PublicationTable {
+    pub fn new(name: &str, filter: Option<String>, columns: Vec<String>) -> Self {
+        Self {
+            table: PublishedTable::parse(name),
+            filter,
+            columns,
+        }
+    }
+
+    fn has_filter(&self) -> bool {
+        self.filter.is_some() || !self.columns.is_empty()
+    }
+
+    /// Table as written in `CREATE PUBLICATION` and `ALTER PUBLICATION ... ADD TABLE`. The
+    /// filter is pasted as is, so statements run through `execute`: the extended protocol
+    /// rejects a filter that smuggles in a second statement.
+    fn sql(&self) -> String {
+        let mut sql = self.table.quoted();
+        if !self.columns.is_empty() {
+            let columns: Vec<_> = self.columns.iter().map(|c| quote_ident(c)).collect();
+            sql.push_str(&format!(" ({})", columns.join(", ")));
+        }
+        if let Some(filter) = &self.filter {
+            sql.push_str(&format!(" WHERE ({})", filter));
+        }
+        sql
+    }
+}
+
+/// Manages the publications that decide what a replication slot decodes
+pub struct Publications {
+    client: Client,
+}
+
+impl Publications {
+    pub async fn connect(connection_string: &str) -> Result<Self, Box<dyn Error>> {
+        let (client, connection) = tokio_postgres::connect(connection_string, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                error!("Connection error: {}", e);
+            }
+        });
+
+        Ok(Self { client })
+    }
+
+    /// Create a publication for `tables`, or for all tables in the database
+    pub async fn create(
+        &self,
+        publication: &str,
+        tables: &[PublicationTable],
+        all_tables: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        if all_tables && !tables.is_empty() {
+            return Err("Tables can't be listed for a publication of all tables".into());
+        }
+        self.check_filters(tables).await?;
+
+        let mut sql = format!("CREATE PUBLICATION {}", quote_ident(publication));
+        if all_tables {
+            sql.push_str(" FOR ALL TABLES");
+        } else if !tables.is_empty() {
+            let tables: Vec<_> = tables.iter().map(PublicationTable::sql).collect();
+            sql.push_str(&format!(" FOR TABLE {}", tables.join(", ")));
+        }
+        self.client.execute(&sql, &[]).await?;
+
+        println!("Created publication '{}'", publication);
+        Ok(())
+    }
+
+    pub async fn add_table(
+        &self,
+        publication: &str,
+        table: &PublicationTable,
+    ) -> Result<(), Box<dyn Error>> {
+        self.check_filters(std::slice::from_ref(table)).await?;
+
+        self.client
+            .execute(
+                &format!(
+                    "ALTER PUBLICATION {} ADD TABLE {}",
+                    quote_ident(publication),
+                    table.sql()
+                ),
+                &[],
+            )
+            .await?;
+
+        println!(
+            "Added table {}.{} to publication '{}'",
+            table.table.schema, table.table.table, publication
+        );
+        Ok(())
+    }
+
+    pub async fn drop_table(
+        &self,
+        publication: &str,
+        table: &PublishedTable,
+    ) -> Result<(), Box<dyn Error>> {
+        self.client
+            .execute(
+                &format!(
+                    "ALTER PUBLICATION {} DROP TABLE {}",
+                    quote_ident(publication),
+                    table.quoted()
+                ),
+                &[],
+            )
+            .await?;
+
+        println!(
+            "Dropped table {}.{} from publication '{}'",
+            table.schema, table.table, publication
+        );
+        Ok(())
+    }
+
+    pub async fn drop(&self, publication: &str) -> Result<(), Box<dyn Error>> {
+        self.client
+            .execute(
+                &format!("DROP PUBLICATION {}", quote_ident(publication)),
+                &[],
+            )
+            .await?;
+
+        println!("Dropped publication '{}'", publication);
+        Ok(())
+    }
+
+    /// Print a publication, or all publications, with their tables, columns and row filters
+    pub async fn show(&self, publication: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let publications = self
+            .client
+            .query(
+                "SELECT pubname::text, puballtables, pubinsert, pubupdate, pubdelete, pubtruncate
+                 FROM pg_publication WHERE $1::text IS NULL OR pubname = $1 ORDER BY pubname",
+                &[&publication],
+            )
+            .await?;
+
+        if let Some(publication) = publication
+            && publications.is_empty()
+        {
+            return Err(format!("No publication named '{}'", publication).into());
+        }
+
+        // Column lists and row filters are only known from PostgreSQL 15
+        let tables_query = if self.server_version().await? >= ROW_FILTER_SERVER_VERSION {
+            "SELECT schemaname::text, tablename::text, attnames::text[], rowfilter
+             FROM pg_publication_tables WHERE pubname = $1 ORDER BY schemaname, tablename"
+        } else {
+            "SELECT schemaname::text, tablename::text, NULL::text[], NULL::text
+             FROM pg_publication_tables WHERE pubname = $1 ORDER BY schemaname, tablename"
+        };
+
+        for row in publications {
+            let name: &str = row.get(0);
+            let all_tables: bool = row.get(1);
+            let operations: Vec<_> = [(2, "insert"), (3, "update"), (4, "delete"), (5, "truncate")]
+                .into_iter()
+                .filter(|&(index, _)| row.get::<_, bool>(index))
+                .map(|(_, operation)| operation)
+                .collect();
+
+            println!(
+                "Name: {} | All tables: {} | Operations: {}",
+                name,
+                all_tables,
+                operations.join(", ")
+            );
+
+            for table in self.client.query(tables_query, &[&name]).await? {
+                let schema: &str = table.get(0);
+                let table_name: &str = table.get(1);
+                let columns: Option<Vec<String>> = table.get(2);
+                let filter: Option<&str> = table.get(3);
+
+                println!(
+                    "  Table: {}.{} | Columns: {} | Filter: {}",
+                    schema,
+                    table_name,
+                    columns.map_or_else(|| "all".to_string(), |columns| columns.join(", ")),
+                    filter.unwrap_or("none")
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Row filters and column lists need PostgreSQL 15+, older servers reject them with a
+    /// syntax error
+    async fn check_filters(&self, tables: &[PublicationTable]) -> Result<(), Box<dyn Error>> {
+        if tables.iter().any(PublicationTable::has_filter)
+            && self.server_version().await? < ROW_FILTER_SERVER_VERSION
+        {
+            return Err("Row filters and column lists require PostgreSQL 15+".into());
+        }
+        Ok(())
+    }
+
+    async fn server_version(&self) -> Result<i32, Box<dyn Error>> {
+        let row = self
+            .client
+            .query_one("SELECT current_setting('server_version_num')::int", &[])
+            .await?;
+        Ok(row.get(0))
+    }
+}
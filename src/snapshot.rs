@@ -0,0 +1,866 @@
+use crate::decode;
+use crate::expression::{Comparison, Expression, Operand, RowImage};
+use bytes::BytesMut;
+use serde_json::{Value, json};
+use std::collections::{HashSet, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_postgres::types::{Format, FromSql, IsNull, ToSql, Type, to_sql_checked};
+use tokio_postgres::{Client, Row, SimpleQueryMessage};
+
+/// Rows fetched from a table per round trip
+pub const FETCH_SIZE: usize = 500;
+
+// Signal types written to and read from the signal table
+pub const SIGNAL_EXECUTE_SNAPSHOT: &str = "execute-snapshot";
+pub const SIGNAL_WINDOW_OPEN: &str = "snapshot-window-open";
+pub const SIGNAL_WINDOW_CLOSE: &str = "snapshot-window-close";
+const SIGNAL_PROGRESS: &str = "snapshot-progress";
+
+/// Table included in a publication
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublishedTable {
+    pub schema: String,
+    pub table: String,
+}
+
+impl PublishedTable {
+    /// Parse a `schema.table` name, tables without a schema are in `public`
+    pub fn parse(name: &str) -> Self {
+        match name.split_once('.') {
+            Some((schema, table)) => Self {
+                schema: schema.to_string(),
+                table: table.to_string(),
+            },
+            None => Self {
+                schema: "public".to_string(),
+                table: name.to_string(),
+            },
+        }
+    }
+
+    pub fn matches(&self, schema: &str, table: &str) -> bool {
+        self.schema == schema && self.table == table
+    }
+
+    pub fn quoted(&self) -> String {
+        format!("{}.{}", quote_ident(&self.schema), quote_ident(&self.table))
+    }
+}
+
+/// Table to read in an incremental snapshot, optionally restricted to rows matching `filter`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotTable {
+    pub table: PublishedTable,
+    /// Row predicate in the grammar of subscription filters, e.g. `created_at > '2024-01-01'`
+    pub filter: Option<String>,
+}
+
+/// Snapshot filter rendered as an SQL condition, with its literals as bound parameters so a
+/// filter can never change the query it is part of
+#[derive(Debug, Clone, PartialEq)]
+pub struct SqlFilter {
+    condition: String,
+    params: Vec<TextParam>,
+}
+
+impl SqlFilter {
+    pub fn parse(filter: &str) -> Result<Self, String> {
+        let mut sql_filter = Self {
+            condition: String::new(),
+            params: Vec::new(),
+        };
+        sql_filter.condition = sql_filter.render(&Expression::parse(filter)?)?;
+        Ok(sql_filter)
+    }
+
+    fn render(&mut self, expression: &Expression) -> Result<String, String> {
+        Ok(match expression {
+            Expression::And(expressions) => self.render_all(expressions, " AND ")?,
+            Expression::Or(expressions) => self.render_all(expressions, " OR ")?,
+            Expression::Not(expression) => format!("NOT ({})", self.render(expression)?),
+            Expression::Compare(left, comparison, right) => {
+                let operator = match comparison {
+                    Comparison::Eq => "=",
+                    Comparison::NotEq => "<>",
+                    Comparison::Lt => "<",
+                    Comparison::LtEq => "<=",
+                    Comparison::Gt => ">",
+                    Comparison::GtEq => ">=",
+                };
+                format!(
+                    "{} {} {}",
+                    self.operand(left)?,
+                    operator,
+                    self.operand(right)?
+                )
+            }
+            Expression::In {
+                operand,
+                list,
+                negated,
+            } => format!(
+                "{} {}IN ({})",
+                self.operand(operand)?,
+                if *negated { "NOT " } else { "" },
+                list.iter()
+                    .map(|item| self.operand(item))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join(", ")
+            ),
+            Expression::IsNull { operand, negated } => format!(
+                "{} IS {}NULL",
+                self.operand(operand)?,
+                if *negated { "NOT " } else { "" }
+            ),
+            Expression::Truthy(operand) => self.operand(operand)?,
+        })
+    }
+
+    fn render_all(
+        &mut self,
+        expressions: &[Expression],
+        separator: &str,
+    ) -> Result<String, String> {
+        Ok(expressions
+            .iter()
+            .map(|expression| Ok(format!("({})", self.render(expression)?)))
+            .collect::<Result<Vec<_>, String>>()?
+            .join(separator))
+    }
+
+    fn operand(&mut self, operand: &Operand) -> Result<String, String> {
+        match operand {
+            Operand::Column(RowImage::Old, _) => {
+                Err("old row columns are not available in snapshots".to_string())
+            }
+            Operand::Column(_, column) => Ok(quote_ident(column)),
+            Operand::Literal(Value::Null) => Ok("NULL".to_string()),
+            Operand::Literal(Value::Bool(value)) => Ok(value.to_string()),
+            Operand::Literal(Value::String(value)) => Ok(self.param(value.clone())),
+            Operand::Literal(value) => Ok(self.param(value.to_string())),
+        }
+    }
+
+    fn param(&mut self, value: String) -> String {
+        self.params.push(TextParam(value));
+        format!("${}", self.params.len())
+    }
+}
+
+impl SnapshotTable {
+    pub fn sql_filter(&self) -> Result<Option<SqlFilter>, String> {
+        self.filter.as_deref().map(SqlFilter::parse).transpose()
+    }
+}
+
+/// Unique id for a signal written to the signal table
+pub fn signal_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("{}-{:x}", std::process::id(), nanos)
+}
+
+/// Id of the low or high watermark row of a snapshot window. The two rows of a window need
+/// distinct ids, as `id` is the primary key of the signal table.
+pub fn watermark_id(window_id: &str, kind: &str) -> String {
+    match kind {
+        SIGNAL_WINDOW_OPEN => format!("{window_id}-open"),
+        _ => format!("{window_id}-close"),
+    }
+}
+
+/// Tables of `publication` in a stable order
+pub async fn publication_tables(
+    client: &Client,
+    publication: &str,
+) -> Result<Vec<PublishedTable>, tokio_postgres::Error> {
+    let rows = client
+        .query(
+            "SELECT schemaname::text, tablename::text FROM pg_publication_tables
+             WHERE pubname = $1
+             ORDER BY schemaname, tablename",
+            &[&publication],
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| PublishedTable {
+            schema: row.get(0),
+            table: row.get(1),
+        })
+        .collect())
+}
+
+/// Replicated columns of a table as (name, type OID), in attribute order. Enums and domains
+/// have the OID of the type they are decoded as, see `decode::resolve_types`.
+pub async fn table_columns(
+    client: &Client,
+    table: &PublishedTable,
+) -> Result<Vec<(String, u32)>, tokio_postgres::Error> {
+    // Generated columns are not replicated by pgoutput, leave them out of snapshots as well
+    let rows = client
+        .query(
+            "SELECT attname::text, atttypid FROM pg_attribute
+             WHERE attrelid = format('%I.%I', $1::text, $2::text)::regclass
+                 AND attnum > 0 AND NOT attisdropped AND attgenerated = ''
+             ORDER BY attnum",
+            &[&table.schema, &table.table],
+        )
+        .await?;
+
+    let type_oids: Vec<u32> = rows.iter().map(|row| row.get(1)).collect();
+    let types = decode::resolve_types(client, &type_oids).await?;
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let type_oid = row.get(1);
+            (
+                row.get(0),
+                types.get(&type_oid).copied().unwrap_or(type_oid),
+            )
+        })
+        .collect())
+}
+
+/// Primary key columns of a table in key order. Empty if the table has no primary key, or if
+/// its replica identity is another index or nothing: deletes only carry the replica identity,
+/// and are matched against the rows of a snapshot window by primary key.
+pub async fn primary_key(
+    client: &Client,
+    table: &PublishedTable,
+) -> Result<Vec<String>, tokio_postgres::Error> {
+    let rows = client
+        .query(
+            "SELECT a.attname::text FROM pg_index i
+             JOIN pg_class c ON c.oid = i.indrelid
+             CROSS JOIN unnest(i.indkey) WITH ORDINALITY AS k(attnum, position)
+             JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = k.attnum
+             WHERE i.indrelid = format('%I.%I', $1::text, $2::text)::regclass AND i.indisprimary
+                 AND (c.relreplident IN ('d', 'f') OR c.relreplident = 'i' AND i.indisreplident)
+             ORDER BY k.position",
+            &[&table.schema, &table.table],
+        )
+        .await?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Reads the rows of a table in batches through a cursor, which requires an open transaction
+pub struct TableCursor<'a> {
+    client: &'a Client,
+    columns: Vec<(String, u32)>,
+    /// Read values in binary format, like pgoutput sends them with `--binary`
+    binary: bool,
+    done: bool,
+}
+
+impl<'a> TableCursor<'a> {
+    pub async fn open(
+        client: &'a Client,
+        table: &PublishedTable,
+        binary: bool,
+    ) -> Result<TableCursor<'a>, tokio_postgres::Error> {
+        let columns = table_columns(client, table).await?;
+
+        client
+            .batch_execute(&format!(
+                "DECLARE walstream_snapshot NO SCROLL CURSOR FOR SELECT {} FROM {}",
+                select_list(&columns),
+                table.quoted()
+            ))
+            .await?;
+
+        Ok(Self {
+            client,
+            columns,
+            binary,
+            done: false,
+        })
+    }
+
+    /// Next batch of rows as JSON objects, None once the table has been read completely
+    pub async fn fetch(&mut self) -> Result<Option<Vec<Value>>, tokio_postgres::Error> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let fetch = format!("FETCH FORWARD {} FROM walstream_snapshot", FETCH_SIZE);
+        let rows: Vec<Value> = if self.binary {
+            self.client
+                .query(&fetch, &[])
+                .await?
+                .iter()
+                .map(|row| binary_row_to_json(&self.columns, row))
+                .collect()
+        } else {
+            self.client
+                .simple_query(&fetch)
+                .await?
+                .iter()
+                .filter_map(|message| match message {
+                    SimpleQueryMessage::Row(row) => {
+                        Some(row_to_json(&self.columns, |i| row.get(i)))
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+
+        if rows.len() < FETCH_SIZE {
+            self.done = true;
+            self.client
+                .batch_execute("CLOSE walstream_snapshot")
+                .await?;
+        }
+
+        Ok(Some(rows))
+    }
+}
+
+/// Row read by an incremental snapshot chunk
+#[derive(Debug)]
+pub struct ChunkRow {
+    /// Primary key values in text format, to continue the next chunk after this row
+    pub key: Vec<String>,
+    pub row: Value,
+}
+
+/// Read up to `limit` rows in primary key order, starting after the row with key `after`.
+/// Values are read in binary format with `binary`, like pgoutput sends them with `--binary`.
+pub async fn read_chunk(
+    client: &Client,
+    target: &SnapshotTable,
+    columns: &[(String, u32)],
+    key_columns: &[String],
+    after: Option<&[String]>,
+    limit: usize,
+    binary: bool,
+) -> Result<Vec<ChunkRow>, Box<dyn std::error::Error + Sync + Send>> {
+    let filter = target.sql_filter()?;
+    let key_list = key_columns
+        .iter()
+        .map(|name| quote_ident(name))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut conditions = Vec::new();
+    // Untyped literals take the types of the key columns in the row comparison
+    if let Some(key) = after {
+        conditions.push(format!(
+            "({}) > ({})",
+            key_list,
+            key.iter()
+                .map(|value| quote_literal(value))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    if let Some(filter) = &filter {
+        conditions.push(format!("({})", filter.condition));
+    }
+    let condition = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+
+    // Without `binary`, values are cast to text to decode them like pgoutput text values. The
+    // key follows in text format to continue the next chunk after it.
+    let select = columns
+        .iter()
+        .map(|(name, _)| {
+            if binary {
+                quote_ident(name)
+            } else {
+                format!("{}::text", quote_ident(name))
+            }
+        })
+        .chain(
+            key_columns
+                .iter()
+                .map(|name| format!("{}::text", quote_ident(name))),
+        )
+        .collect::<Vec<_>>()
+        .join(", ");
+    // Qualified, as an output column of the same name would take precedence in ORDER BY
+    let order = key_columns
+        .iter()
+        .map(|name| format!("{}.{}", target.table.quoted(), quote_ident(name)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let rows = client
+        .query(
+            &format!(
+                "SELECT {} FROM {} {} ORDER BY {} LIMIT {}",
+                select,
+                target.table.quoted(),
+                condition,
+                order,
+                limit
+            ),
+            &filter
+                .iter()
+                .flat_map(|filter| &filter.params)
+                .map(|param| param as &(dyn ToSql + Sync))
+                .collect::<Vec<_>>(),
+        )
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| ChunkRow {
+            key: (columns.len()..columns.len() + key_columns.len())
+                .map(|i| row.get::<_, Option<String>>(i).unwrap_or_default())
+                .collect(),
+            row: if binary {
+                binary_row_to_json(columns, row)
+            } else {
+                row_to_json(columns, |i| row.get::<_, Option<&str>>(i))
+            },
+        })
+        .collect())
+}
+
+/// Chunk of an incremental snapshot, bracketed by watermarks in the signal table. Rows
+/// changed in the stream between the watermarks are newer than the chunk and are dropped.
+#[derive(Debug)]
+pub struct SnapshotWindow {
+    pub id: String,
+    pub table: PublishedTable,
+    pub open: bool,
+    key_columns: Vec<String>,
+    rows: Vec<ChunkRow>,
+    changed_keys: HashSet<String>,
+}
+
+impl SnapshotWindow {
+    pub fn new(
+        id: String,
+        table: PublishedTable,
+        key_columns: Vec<String>,
+        rows: Vec<ChunkRow>,
+    ) -> Self {
+        Self {
+            id,
+            table,
+            open: false,
+            key_columns,
+            rows,
+            changed_keys: HashSet::new(),
+        }
+    }
+
+    /// Record a row changed in the stream, the row may be the new row or only its key
+    pub fn changed(&mut self, row: &Value) {
+        if self.open {
+            self.changed_keys.insert(row_key(&self.key_columns, row));
+        }
+    }
+
+    /// Number of rows read for the chunk, including the dropped ones
+    pub fn rows_read(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Primary key of the last row read for the chunk
+    pub fn last_key(&self) -> Option<Vec<String>> {
+        self.rows.last().map(|row| row.key.clone())
+    }
+
+    /// Rows of the chunk that were not changed while the window was open
+    pub fn into_rows(self) -> Vec<Value> {
+        let changed_keys = self.changed_keys;
+        let key_columns = self.key_columns;
+
+        self.rows
+            .into_iter()
+            .map(|chunk_row| chunk_row.row)
+            .filter(|row| !changed_keys.contains(&row_key(&key_columns, row)))
+            .collect()
+    }
+}
+
+/// Progress of an incremental snapshot, persisted in the signal table to survive restarts
+#[derive(Debug, Default)]
+pub struct IncrementalSnapshot {
+    /// Tables still to be read, the first one is in progress
+    pub tables: VecDeque<SnapshotTable>,
+    /// Primary key of the last row read from the table in progress
+    pub last_key: Option<Vec<String>>,
+    /// Rows sent for the table in progress
+    pub row_count: u64,
+}
+
+impl IncrementalSnapshot {
+    fn to_json(&self) -> Value {
+        json!({
+            "tables": self
+                .tables
+                .iter()
+                .map(|t| json!([t.table.schema, t.table.table, t.filter]))
+                .collect::<Vec<_>>(),
+            "last_key": self.last_key,
+            "row_count": self.row_count,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let tables = value
+            .get("tables")?
+            .as_array()?
+            .iter()
+            .map(|t| {
+                Some(SnapshotTable {
+                    table: PublishedTable {
+                        schema: t.get(0)?.as_str()?.to_string(),
+                        table: t.get(1)?.as_str()?.to_string(),
+                    },
+                    filter: t.get(2).and_then(Value::as_str).map(str::to_string),
+                })
+            })
+            .collect::<Option<VecDeque<_>>>()?;
+
+        let last_key = match value.get("last_key") {
+            Some(Value::Array(key)) => Some(
+                key.iter()
+                    .map(|v| v.as_str().map(str::to_string))
+                    .collect::<Option<Vec<_>>>()?,
+            ),
+            _ => None,
+        };
+
+        Some(Self {
+            tables,
+            last_key,
+            row_count: value.get("row_count").and_then(Value::as_u64).unwrap_or(0),
+        })
+    }
+}
+
+/// Table receiving snapshot signals and watermarks, expected to be included in the publication:
+/// `CREATE TABLE walstream_signal (id text PRIMARY KEY, type text NOT NULL, data text)`
+#[derive(Debug, Clone)]
+pub struct SignalTable {
+    pub table: PublishedTable,
+    pub chunk_size: usize,
+}
+
+/// Row inserted into the signal table
+#[derive(Debug)]
+pub struct Signal {
+    pub id: String,
+    pub kind: String,
+    pub data: Option<String>,
+}
+
+impl Signal {
+    pub fn from_json(row: &Value) -> Option<Self> {
+        Some(Self {
+            id: row.get("id")?.as_str()?.to_string(),
+            kind: row.get("type")?.as_str()?.to_string(),
+            data: row.get("data").and_then(Value::as_str).map(str::to_string),
+        })
+    }
+
+    /// Tables listed in the `data-collections` of an execute-snapshot signal, with their
+    /// filter from `additional-conditions`
+    pub fn data_collections(&self) -> Vec<SnapshotTable> {
+        let Some(data) = self
+            .data
+            .as_deref()
+            .and_then(|data| serde_json::from_str::<Value>(data).ok())
+        else {
+            return Vec::new();
+        };
+
+        let conditions = data
+            .get("additional-conditions")
+            .and_then(Value::as_array)
+            .cloned()
+            .unwrap_or_default();
+        let filter = |table: &PublishedTable| {
+            conditions.iter().find_map(|condition| {
+                let collection = condition.get("data-collection")?.as_str()?;
+                (PublishedTable::parse(collection) == *table)
+                    .then(|| condition.get("filter")?.as_str().map(str::to_string))?
+            })
+        };
+
+        data.get("data-collections")
+            .and_then(Value::as_array)
+            .map(|tables| {
+                tables
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(PublishedTable::parse)
+                    .map(|table| SnapshotTable {
+                        filter: filter(&table),
+                        table,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl SignalTable {
+    /// Request an incremental snapshot by writing an execute-snapshot signal
+    pub async fn execute_snapshot(
+        &self,
+        client: &Client,
+        id: &str,
+        targets: &[SnapshotTable],
+    ) -> Result<(), tokio_postgres::Error> {
+        let collection = |t: &SnapshotTable| format!("{}.{}", t.table.schema, t.table.table);
+        let data = json!({
+            "data-collections": targets.iter().map(collection).collect::<Vec<_>>(),
+            "additional-conditions": targets
+                .iter()
+                .filter_map(|t| {
+                    let filter = t.filter.as_ref()?;
+                    Some(json!({"data-collection": collection(t), "filter": filter}))
+                })
+                .collect::<Vec<_>>(),
+        });
+
+        client
+            .execute(
+                &format!(
+                    "INSERT INTO {} (id, type, data) VALUES ($1, $2, $3)",
+                    self.table.quoted()
+                ),
+                &[&id, &SIGNAL_EXECUTE_SNAPSHOT, &data.to_string()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Write the low or high watermark of a snapshot window
+    pub async fn write_watermark(
+        &self,
+        client: &Client,
+        window_id: &str,
+        kind: &str,
+    ) -> Result<(), tokio_postgres::Error> {
+        client
+            .execute(
+                &format!(
+                    "INSERT INTO {} (id, type) VALUES ($1, $2)",
+                    self.table.quoted()
+                ),
+                &[&watermark_id(window_id, kind), &kind],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Remove both watermarks of a snapshot window once it is closed or abandoned
+    pub async fn delete_watermarks(
+        &self,
+        client: &Client,
+        window_id: &str,
+    ) -> Result<(), tokio_postgres::Error> {
+        client
+            .execute(
+                &format!("DELETE FROM {} WHERE id IN ($1, $2)", self.table.quoted()),
+                &[
+                    &watermark_id(window_id, SIGNAL_WINDOW_OPEN),
+                    &watermark_id(window_id, SIGNAL_WINDOW_CLOSE),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, client: &Client, id: &str) -> Result<u64, tokio_postgres::Error> {
+        client
+            .execute(
+                &format!("DELETE FROM {} WHERE id = $1", self.table.quoted()),
+                &[&id],
+            )
+            .await
+    }
+
+    pub async fn load_progress(
+        &self,
+        client: &Client,
+    ) -> Result<Option<IncrementalSnapshot>, tokio_postgres::Error> {
+        let rows = client
+            .query(
+                &format!("SELECT data FROM {} WHERE id = $1", self.table.quoted()),
+                &[&SIGNAL_PROGRESS],
+            )
+            .await?;
+
+        Ok(rows
+            .first()
+            .and_then(|row| row.get::<_, Option<String>>(0))
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .and_then(|data| IncrementalSnapshot::from_json(&data)))
+    }
+
+    pub async fn save_progress(
+        &self,
+        client: &Client,
+        progress: &IncrementalSnapshot,
+    ) -> Result<(), tokio_postgres::Error> {
+        client
+            .execute(
+                &format!(
+                    "INSERT INTO {} (id, type, data) VALUES ($1, $1, $2)
+                     ON CONFLICT (id) DO UPDATE SET data = EXCLUDED.data",
+                    self.table.quoted()
+                ),
+                &[&SIGNAL_PROGRESS, &progress.to_json().to_string()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn clear_progress(&self, client: &Client) -> Result<(), tokio_postgres::Error> {
+        self.delete(client, SIGNAL_PROGRESS).await?;
+        Ok(())
+    }
+}
+
+/// Primary key values of a row as a comparable string. Chunk rows and streamed rows are
+/// decoded in the same format (see `read_chunk`), so equal keys give equal strings.
+fn row_key(key_columns: &[String], row: &Value) -> String {
+    Value::Array(
+        key_columns
+            .iter()
+            .map(|key| row.get(key).cloned().unwrap_or(Value::Null))
+            .collect(),
+    )
+    .to_string()
+}
+
+/// Decode a row of values in text format, like pgoutput sends them by default
+fn row_to_json<'a>(columns: &[(String, u32)], get: impl Fn(usize) -> Option<&'a str>) -> Value {
+    let mut obj = serde_json::Map::new();
+    for (i, (name, type_oid)) in columns.iter().enumerate() {
+        let value = match get(i) {
+            Some(text) => decode::decode_text(*type_oid, text),
+            None => Value::Null,
+        };
+        obj.insert(name.clone(), value);
+    }
+    Value::Object(obj)
+}
+
+/// Value in binary format, which tokio-postgres receives query results in
+struct BinaryValue<'a>(&'a [u8]);
+
+impl<'a> FromSql<'a> for BinaryValue<'a> {
+    fn from_sql(_: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(Self(raw))
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+}
+
+/// Filter literal in text format, the server parses it as the type it is compared with like a
+/// quoted literal in a `WHERE` clause
+#[derive(Debug, Clone, PartialEq)]
+struct TextParam(String);
+
+impl ToSql for TextParam {
+    fn to_sql(
+        &self,
+        _: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        out.extend_from_slice(self.0.as_bytes());
+        Ok(IsNull::No)
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+
+    fn encode_format(&self, _: &Type) -> Format {
+        Format::Text
+    }
+
+    to_sql_checked!();
+}
+
+/// Decode a row of values in binary format, like pgoutput sends them with `--binary`
+fn binary_row_to_json(columns: &[(String, u32)], row: &Row) -> Value {
+    let mut obj = serde_json::Map::new();
+    for (i, (name, type_oid)) in columns.iter().enumerate() {
+        let value = match row.get::<_, Option<BinaryValue>>(i) {
+            Some(BinaryValue(bytes)) => decode::decode_binary(*type_oid, bytes),
+            None => Value::Null,
+        };
+        obj.insert(name.clone(), value);
+    }
+    Value::Object(obj)
+}
+
+fn select_list(columns: &[(String, u32)]) -> String {
+    columns
+        .iter()
+        .map(|(name, _)| quote_ident(name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(filter: &str) -> (String, Vec<String>) {
+        let sql_filter = SqlFilter::parse(filter).unwrap();
+        let params = sql_filter.params.into_iter().map(|param| param.0).collect();
+        (sql_filter.condition, params)
+    }
+
+    #[test]
+    fn sql_filter_binds_literals() {
+        assert_eq!(
+            render("status IN ('paid', 'it''s') AND (total >= 100 OR NOT new.\"Flag\")"),
+            (
+                r#"("status" IN ($1, $2)) AND (("total" >= $3) OR (NOT ("Flag")))"#.to_string(),
+                vec!["paid".to_string(), "it's".to_string(), "100".to_string()]
+            )
+        );
+        assert_eq!(
+            render("a <> 1.5 AND b IS NOT NULL AND c NOT IN (true, NULL)"),
+            (
+                r#"("a" <> $1) AND ("b" IS NOT NULL) AND ("c" NOT IN (true, NULL))"#.to_string(),
+                vec!["1.5".to_string()]
+            )
+        );
+        assert_eq!(
+            render(r#""x""; DROP TABLE t; --" = '1''); DROP TABLE t; --'"#),
+            (
+                r#""x""; DROP TABLE t; --" = $1"#.to_string(),
+                vec!["1'); DROP TABLE t; --".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn sql_filter_rejects_invalid_filters() {
+        assert!(SqlFilter::parse("id = 1; DROP TABLE t").is_err());
+        assert!(SqlFilter::parse("created_at > now()").is_err());
+        assert_eq!(
+            SqlFilter::parse("old.id = 1"),
+            Err("old row columns are not available in snapshots".to_string())
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use crate::config::SourceConfig;
+use crate::postgres::ReplicationOptions;
+use crate::snapshot::{PublishedTable, SignalTable};
+use std::collections::HashSet;
+use std::error::Error;
+
+/// Database streamed by a replication task of its own, its events are tagged with `name`
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub name: String,
+    pub connection: String,
+    pub publication: String,
+    pub slot: String,
+    pub temporary: bool,
+    pub options: ReplicationOptions,
+}
+
+impl Source {
+    /// Sources with the options they leave out taken from `defaults`, and the connection from
+    /// `connection` if they have none
+    pub fn resolve(
+        configs: Vec<SourceConfig>,
+        connection: Option<&str>,
+        temporary: bool,
+        defaults: &ReplicationOptions,
+        snapshot_chunk_size: usize,
+    ) -> Result<Vec<Self>, Box<dyn Error>> {
+        let mut names = HashSet::new();
+        let mut sources = Vec::with_capacity(configs.len());
+        for config in configs {
+            check_name(&config.name)?;
+            if !names.insert(config.name.clone()) {
+                return Err(format!("Duplicate source name '{}'", config.name).into());
+            }
+            let connection = config
+                .connection
+                .or(connection.map(str::to_string))
+                .ok_or_else(|| format!("Source '{}' has no connection", config.name))?;
+
+            let replication = config.replication;
+            let chunk_size = replication
+                .snapshot_chunk_size
+                .unwrap_or(snapshot_chunk_size);
+            // An empty chunk never completes its table
+            if chunk_size == 0 {
+                return Err(
+                    format!("Source '{}' has a snapshot chunk size of 0", config.name).into(),
+                );
+            }
+            let signal_table = match replication.signal_table {
+                Some(name) => Some(SignalTable {
+                    table: PublishedTable::parse(&name),
+                    chunk_size,
+                }),
+                None => defaults
+                    .signal_table
+                    .clone()
+                    .map(|signal_table| SignalTable {
+                        chunk_size,
+                        ..signal_table
+                    }),
+            };
+
+            sources.push(Self {
+                name: config.name,
+                connection,
+                publication: config.publication,
+                slot: config.slot,
+                temporary: replication.temporary.unwrap_or(temporary),
+                options: ReplicationOptions {
+                    streaming: replication.streaming.unwrap_or(defaults.streaming),
+                    two_phase: replication.two_phase.unwrap_or(defaults.two_phase),
+                    messages: replication.messages.unwrap_or(defaults.messages),
+                    binary: replication.binary.unwrap_or(defaults.binary),
+                    snapshot: replication.snapshot.unwrap_or(defaults.snapshot),
+                    signal_table,
+                    transaction_memory_limit: defaults.transaction_memory_limit,
+                    lossless: replication.lossless.unwrap_or(defaults.lossless),
+                },
+            });
+        }
+        Ok(sources)
+    }
+}
+
+/// Names are also used for the directories of a source under --data-dir and --log-dir
+pub fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "Source name '{}' must only contain letters, digits, '-' and '_'",
+            name
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ReplicationConfig;
+
+    fn defaults() -> ReplicationOptions {
+        ReplicationOptions {
+            streaming: false,
+            two_phase: false,
+            messages: false,
+            binary: false,
+            snapshot: false,
+            signal_table: Some(SignalTable {
+                table: PublishedTable::parse("public.walstream_signal"),
+                chunk_size: 1024,
+            }),
+            transaction_memory_limit: 1 << 20,
+            lossless: false,
+        }
+    }
+
+    fn config(name: &str, replication: ReplicationConfig) -> SourceConfig {
+        SourceConfig {
+            name: name.to_string(),
+            connection: None,
+            publication: "pub".to_string(),
+            slot: format!("{}_slot", name),
+            replication,
+        }
+    }
+
+    #[test]
+    fn sources_take_left_out_options_from_the_defaults() {
+        let sources = Source::resolve(
+            vec![
+                config("orders", ReplicationConfig::default()),
+                config(
+                    "billing",
+                    ReplicationConfig {
+                        binary: Some(true),
+                        snapshot_chunk_size: Some(10),
+                        ..Default::default()
+                    },
+                ),
+            ],
+            Some("host=localhost"),
+            true,
+            &defaults(),
+            1024,
+        )
+        .unwrap();
+
+        assert_eq!(sources[0].connection, "host=localhost");
+        assert!(sources[0].temporary);
+        assert!(!sources[0].options.binary);
+        assert_eq!(
+            sources[0].options.signal_table.as_ref().unwrap().chunk_size,
+            1024
+        );
+        assert!(sources[1].options.binary);
+        assert_eq!(
+            sources[1].options.signal_table.as_ref().unwrap().chunk_size,
+            10
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_sources() {
+        let resolve = |configs, connection| {
+            Source::resolve(configs, connection, true, &defaults(), 1024)
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            resolve(
+                vec![config(
+                    "orders",
+                    ReplicationConfig {
+                        snapshot_chunk_size: Some(0),
+                        ..Default::default()
+                    }
+                )],
+                Some("host=localhost")
+            ),
+            "Source 'orders' has a snapshot chunk size of 0"
+        );
+        assert_eq!(
+            resolve(vec![config("orders", ReplicationConfig::default())], None),
+            "Source 'orders' has no connection"
+        );
+        assert_eq!(
+            resolve(
+                vec![
+                    config("orders", ReplicationConfig::default()),
+                    config("orders", ReplicationConfig::default())
+                ],
+                Some("host=localhost")
+            ),
+            "Duplicate source name 'orders'"
+        );
+    }
+}
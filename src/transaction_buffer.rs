@@ -0,0 +1,259 @@
+use prost::Message;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{debug, warn};
+
+/// Counter for unique spill file names within this process
+static SPILL_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Buffers the changes of a single transaction in memory, spilling them
+/// to a temporary file once `memory_limit` bytes have been buffered
+pub struct TransactionBuffer<T> {
+    memory_limit: usize,
+    memory_used: usize,
+    changes: Vec<T>,
+    spill: Option<SpillFile>,
+}
+
+struct SpillFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    /// Number of changes written to the file
+    len: usize,
+}
+
+/// Buffered changes in the order they were pushed. Spilled changes are read back from the file
+/// one at a time, so a drained transaction never has to fit in memory.
+pub struct Drain<T> {
+    changes: std::vec::IntoIter<T>,
+    spill: Option<SpillReader>,
+}
+
+struct SpillReader {
+    reader: BufReader<File>,
+    remaining: usize,
+    /// Removes the file once the changes were read
+    _file: SpillFile,
+}
+
+impl<T: Message + Default> TransactionBuffer<T> {
+    pub fn new(memory_limit: usize) -> Self {
+        Self {
+            memory_limit,
+            memory_used: 0,
+            changes: Vec::new(),
+            spill: None,
+        }
+    }
+
+    pub fn push(&mut self, change: T) -> std::io::Result<()> {
+        if let Some(spill) = self.spill.as_mut() {
+            return spill.write(&change);
+        }
+
+        self.memory_used += change.encoded_len();
+        self.changes.push(change);
+
+        if self.memory_used > self.memory_limit {
+            let path = std::env::temp_dir().join(format!(
+                "walstream-{}-{}.spill",
+                std::process::id(),
+                SPILL_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            debug!(
+                "Transaction exceeded {} bytes, spilling to {}",
+                self.memory_limit,
+                path.display()
+            );
+
+            let mut spill = SpillFile {
+                writer: BufWriter::new(File::create(&path)?),
+                path,
+                len: 0,
+            };
+            for change in self.changes.drain(..) {
+                spill.write(&change)?;
+            }
+            self.memory_used = 0;
+            self.spill = Some(spill);
+        }
+
+        Ok(())
+    }
+
+    /// Take all buffered changes in the order they were pushed, leaving the buffer empty
+    pub fn drain(&mut self) -> std::io::Result<Drain<T>> {
+        self.memory_used = 0;
+        let spill = match self.spill.take() {
+            Some(mut spill) => {
+                spill.writer.flush()?;
+                Some(SpillReader {
+                    reader: BufReader::new(File::open(&spill.path)?),
+                    remaining: spill.len,
+                    _file: spill,
+                })
+            }
+            None => None,
+        };
+
+        Ok(Drain {
+            changes: std::mem::take(&mut self.changes).into_iter(),
+            spill,
+        })
+    }
+
+    /// Keep only the changes for which `f` returns true
+    pub fn retain(&mut self, mut f: impl FnMut(&T) -> bool) -> std::io::Result<()> {
+        let mut retained = Self::new(self.memory_limit);
+        for change in self.drain()? {
+            let change = change?;
+            if f(&change) {
+                retained.push(change)?;
+            }
+        }
+        *self = retained;
+        Ok(())
+    }
+}
+
+impl<T> Default for Drain<T> {
+    fn default() -> Self {
+        Self {
+            changes: Vec::new().into_iter(),
+            spill: None,
+        }
+    }
+}
+
+impl<T: Message + Default> Iterator for Drain<T> {
+    type Item = std::io::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(change) = self.changes.next() {
+            return Some(Ok(change));
+        }
+
+        let spill = self.spill.as_mut()?;
+        if spill.remaining == 0 {
+            return None;
+        }
+        spill.remaining -= 1;
+        Some(read_change(&mut spill.reader))
+    }
+}
+
+impl SpillFile {
+    fn write(&mut self, change: &impl Message) -> std::io::Result<()> {
+        let len = u32::try_from(change.encoded_len())
+            .map_err(|_| std::io::Error::other("Change too large to spill"))?;
+        self.writer.write_all(&len.to_be_bytes())?;
+        self.writer.write_all(&change.encode_to_vec())?;
+        self.len += 1;
+        Ok(())
+    }
+}
+
+/// Change written by `SpillFile::write`, prefixed with its length
+fn read_change<T: Message + Default>(reader: &mut impl Read) -> std::io::Result<T> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let mut bytes = vec![0u8; u32::from_be_bytes(len) as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(T::decode(bytes.as_slice())?)
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Failed to remove spill file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Change {
+        #[prost(uint64, tag = "1")]
+        id: u64,
+        #[prost(string, tag = "2")]
+        data: String,
+    }
+
+    fn change(id: u64) -> Change {
+        Change {
+            id,
+            data: "x".repeat(100),
+        }
+    }
+
+    fn ids(buffer: &mut TransactionBuffer<Change>) -> Vec<u64> {
+        buffer
+            .drain()
+            .unwrap()
+            .map(|change| change.unwrap().id)
+            .collect()
+    }
+
+    #[test]
+    fn drains_in_memory_changes_in_order() {
+        let mut buffer = TransactionBuffer::new(1 << 20);
+        for id in 0..10 {
+            buffer.push(change(id)).unwrap();
+        }
+        assert!(buffer.spill.is_none());
+        assert_eq!(ids(&mut buffer), (0..10).collect::<Vec<_>>());
+        assert_eq!(ids(&mut buffer), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn spills_beyond_memory_limit_and_reads_back_in_order() {
+        let mut buffer = TransactionBuffer::new(500);
+        for id in 0..100 {
+            buffer.push(change(id)).unwrap();
+        }
+        assert!(buffer.changes.is_empty());
+        let path = buffer.spill.as_ref().unwrap().path.clone();
+        assert!(path.exists());
+
+        let mut drain = buffer.drain().unwrap();
+        assert_eq!(drain.next().unwrap().unwrap(), change(0));
+        let rest: Vec<_> = drain.by_ref().map(|change| change.unwrap().id).collect();
+        assert_eq!(rest, (1..100).collect::<Vec<_>>());
+        drop(drain);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn buffer_is_reusable_after_draining_a_spill() {
+        let mut buffer = TransactionBuffer::new(500);
+        for id in 0..10 {
+            buffer.push(change(id)).unwrap();
+        }
+        assert_eq!(ids(&mut buffer).len(), 10);
+
+        buffer.push(change(42)).unwrap();
+        assert!(buffer.spill.is_none());
+        assert_eq!(ids(&mut buffer), vec![42]);
+    }
+
+    #[test]
+    fn retain_keeps_order_in_memory_and_spilled() {
+        for memory_limit in [1 << 20, 500] {
+            let mut buffer = TransactionBuffer::new(memory_limit);
+            for id in 0..50 {
+                buffer.push(change(id)).unwrap();
+            }
+            buffer.retain(|change| change.id % 3 == 0).unwrap();
+            buffer.push(change(99)).unwrap();
+
+            let mut expected: Vec<_> = (0..50).filter(|id| id % 3 == 0).collect();
+            expected.push(99);
+            assert_eq!(ids(&mut buffer), expected);
+        }
+    }
+}